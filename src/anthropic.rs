@@ -0,0 +1,701 @@
+//! Anthropic Messages API (`/v1/messages`) on top of the chat pipeline
+
+use crate::error::Error;
+use crate::model::{
+    ChatRequest, Content, ContentItem, DuckChatCompletion, Message, Role, Stop, gen_id,
+};
+use crate::process::{
+    CompletionHook, EventResult, OutputLimit, process_stream_until,
+    process_stream_with_chunk_until, sse_response,
+};
+use crate::serve::AppState;
+use crate::session::conversation_id;
+use crate::tokenizer;
+use axum::{
+    Json,
+    extract::{State, rejection::JsonRejection},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response, sse::Event},
+};
+use axum_extra::{
+    TypedHeader,
+    extract::WithRejection,
+    headers::{Authorization, authorization::Bearer},
+};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    ops::ControlFlow,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+/// Header carrying the API key in Anthropic clients
+const API_KEY_HEADER: &str = "x-api-key";
+
+// ==================== Request Body ====================
+#[derive(Debug, Deserialize)]
+pub struct MessagesRequest {
+    pub model: String,
+    pub messages: Vec<AnthropicMessage>,
+    #[serde(default)]
+    pub system: Option<AnthropicContent>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    #[serde(default)]
+    pub stream: Option<bool>,
+    #[serde(default)]
+    pub metadata: Option<Metadata>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Metadata {
+    #[serde(default)]
+    pub user_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnthropicMessage {
+    pub role: Role,
+    pub content: AnthropicContent,
+}
+
+/// Text or blocks; images, documents and tool blocks are left to the
+/// `unsupported_content` policy
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum AnthropicContent {
+    Text(String),
+    Blocks(Vec<ContentItem>),
+}
+
+impl From<AnthropicContent> for Content {
+    fn from(content: AnthropicContent) -> Self {
+        match content {
+            AnthropicContent::Text(text) => Content::Text(text),
+            AnthropicContent::Blocks(blocks) => Content::Vec(blocks),
+        }
+    }
+}
+
+impl MessagesRequest {
+    /// Take the system prompt as the first user turn, the upstream having
+    /// no system role
+    fn take_system(&mut self) -> Option<Message> {
+        let system = Content::from(self.system.take()?);
+        Some(
+            Message::builder()
+                .role(Role::User)
+                .content(Content::Text(system.to_text()))
+                .build(),
+        )
+    }
+}
+
+/// Request without its system prompt, see [`MessagesRequest::take_system`]
+impl From<MessagesRequest> for ChatRequest {
+    fn from(req: MessagesRequest) -> Self {
+        let messages = req
+            .messages
+            .into_iter()
+            .map(|message| {
+                Message::builder()
+                    .role(message.role.upstream())
+                    .content(Content::from(message.content))
+                    .build()
+            })
+            .collect();
+
+        ChatRequest::builder()
+            .model(&req.model)
+            .messages(messages)
+            .max_tokens(req.max_tokens)
+            .stop(Some(Stop::Many(req.stop_sequences)))
+            .stream(req.stream)
+            .user(req.metadata.and_then(|metadata| metadata.user_id))
+            .build()
+    }
+}
+
+// ==================== Response Body ====================
+#[derive(Serialize)]
+struct MessageResponse<'a> {
+    id: &'a str,
+    #[serde(rename = "type")]
+    r#type: &'static str,
+    role: Role,
+    model: &'a str,
+    content: Vec<serde_json::Value>,
+    stop_reason: Option<&'static str>,
+    stop_sequence: Option<String>,
+    usage: AnthropicUsage,
+}
+
+#[derive(Serialize)]
+struct AnthropicUsage {
+    input_tokens: u32,
+    output_tokens: u32,
+}
+
+pub async fn messages(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    headers: HeaderMap,
+    WithRejection(Json(mut req), _): WithRejection<Json<MessagesRequest>, AnthropicError>,
+) -> Result<Response, AnthropicError> {
+    let api_key = headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let key = match bearer {
+        Some(bearer) => state.valid_key(Some(bearer))?,
+        None => state.valid_api_key(api_key)?,
+    };
+
+    let system = req.take_system();
+    let mut body = ChatRequest::from(req);
+    // content errors name the blocks of `messages`, which excludes the system prompt
+    state.resolve_request(key, &mut body)?;
+    body.messages.splice(0..0, system);
+    let conversation = conversation_id(&headers, body.user.as_deref(), key);
+    let input_tokens = tokenizer::count_messages(&body.model, &body.messages);
+    let output = OutputLimit::new(&body.model, body.output_limit(), body.stop_sequences());
+    let stream = body.stream.unwrap_or_default();
+    let model = body.model.clone();
+    let keep_alive = state.sse_keep_alive;
+    let start = async move { crate::route::forward(&state, &mut body, conversation).await };
+    if stream {
+        // show activity while the token and upstream requests are in flight
+        return Ok(stream_response(
+            start,
+            model,
+            input_tokens,
+            output,
+            keep_alive,
+        ));
+    }
+    let (resp, on_complete) = start.await?;
+    Ok(single_response(resp, model, input_tokens, output, on_complete).await?)
+}
+
+fn event(data: serde_json::Value) -> EventResult {
+    let name = data["type"].as_str().unwrap_or("message").to_owned();
+    Event::default().event(name).json_data(data)
+}
+
+/// Anthropic `error` event of a reply that failed mid-stream
+fn error_event(err: Error) -> EventResult {
+    let (_, body, _) = error_parts(err);
+    event(body)
+}
+
+/// Status, Anthropic error body and upstream `Retry-After` of `err`
+fn error_parts(err: Error) -> (StatusCode, serde_json::Value, Option<String>) {
+    let (status, error, retry_after) = err.parts();
+    let error = serde_json::to_value(error).unwrap_or_default();
+    // Anthropic errors have no `param`, the message names the field instead
+    let message = match (error["param"].as_str(), error["message"].as_str()) {
+        (Some(param), Some(message)) => format!("{param}: {message}"),
+        (_, message) => message.unwrap_or_default().to_owned(),
+    };
+    let kind = match status {
+        StatusCode::BAD_REQUEST => "invalid_request_error",
+        StatusCode::UNAUTHORIZED => "authentication_error",
+        StatusCode::FORBIDDEN => "permission_error",
+        StatusCode::NOT_FOUND => "not_found_error",
+        StatusCode::TOO_MANY_REQUESTS => "rate_limit_error",
+        StatusCode::SERVICE_UNAVAILABLE => "overloaded_error",
+        _ => "api_error",
+    };
+    let body = json!({
+        "type": "error",
+        "error": {"type": kind, "message": message},
+    });
+    (status, body, retry_after)
+}
+
+/// Error of the Messages API, answered in the Anthropic error shape
+pub struct AnthropicError(Error);
+
+impl From<Error> for AnthropicError {
+    fn from(err: Error) -> Self {
+        Self(err)
+    }
+}
+
+impl From<JsonRejection> for AnthropicError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self(rejection.into())
+    }
+}
+
+impl IntoResponse for AnthropicError {
+    fn into_response(self) -> Response {
+        let (status, body, retry_after) = error_parts(self.0);
+        let mut resp = (status, Json(body)).into_response();
+        // pass the upstream `Retry-After` on to the client
+        if let Some(value) = retry_after.and_then(|value| HeaderValue::from_str(&value).ok()) {
+            resp.headers_mut().insert(header::RETRY_AFTER, value);
+        }
+        resp
+    }
+}
+
+/// Reply held to the request's `max_tokens` and `stop_sequences`
+struct Reply {
+    output: OutputLimit,
+    /// Finish reason of a reply cut short by a limit
+    cut: Option<&'static str>,
+}
+
+impl Reply {
+    fn new(output: OutputLimit) -> Self {
+        Self { output, cut: None }
+    }
+
+    /// Text of `delta` to forward, and whether a limit ended the reply
+    fn push(&mut self, delta: &str) -> (String, bool) {
+        let (text, cut) = self.output.push(delta);
+        self.cut = cut;
+        (text, cut.is_some())
+    }
+
+    /// Text held back until the upstream reply is complete
+    fn finish(&mut self) -> String {
+        if self.cut.is_some() {
+            return String::new();
+        }
+        let (text, cut) = self.output.flush();
+        self.cut = cut;
+        text
+    }
+
+    /// Anthropic `stop_reason` and `stop_sequence` of the reply
+    fn stop_reason(&self) -> (&'static str, Option<String>) {
+        match self.cut {
+            Some("length") => ("max_tokens", None),
+            Some(_) => (
+                "stop_sequence",
+                self.output.stop_sequence().map(ToOwned::to_owned),
+            ),
+            None => ("end_turn", None),
+        }
+    }
+}
+
+fn text_delta(text: String) -> Vec<EventResult> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    vec![event(json!({
+        "type": "content_block_delta",
+        "index": 0,
+        "delta": {"type": "text_delta", "text": text},
+    }))]
+}
+
+/// Stream a reply whose upstream request `start` makes once the message
+/// has opened
+fn stream_response<F>(
+    start: F,
+    model: String,
+    input_tokens: u32,
+    output: OutputLimit,
+    keep_alive: Option<Duration>,
+) -> Response
+where
+    F: Future<Output = crate::Result<(reqwest::Response, Option<CompletionHook>)>> + Send + 'static,
+{
+    let id = gen_id("msg");
+    let opening = vec![
+        event(json!({
+            "type": "message_start",
+            "message": MessageResponse {
+                id: &id,
+                r#type: "message",
+                role: Role::Assistant,
+                model: &model,
+                content: Vec::new(),
+                stop_reason: None,
+                stop_sequence: None,
+                usage: AnthropicUsage {
+                    input_tokens,
+                    output_tokens: 0,
+                },
+            },
+        })),
+        event(json!({
+            "type": "content_block_start",
+            "index": 0,
+            "content_block": {"type": "text", "text": ""},
+        })),
+    ];
+
+    let events = async_stream::stream! {
+        for event in opening {
+            yield event;
+        }
+        match start.await {
+            Ok((resp, on_complete)) => {
+                let mut events = std::pin::pin!(reply_events(resp, model, output, on_complete));
+                while let Some(event) = events.next().await {
+                    yield event;
+                }
+            }
+            Err(err) => yield error_event(err),
+        }
+    };
+    sse_response(events, keep_alive)
+}
+
+/// Events of the upstream reply, after the opening ones
+fn reply_events(
+    resp: reqwest::Response,
+    model: String,
+    output: OutputLimit,
+    on_complete: Option<CompletionHook>,
+) -> impl Stream<Item = EventResult> {
+    let reply = Arc::new(Mutex::new(Reply::new(output)));
+    let reply_end = reply.clone();
+
+    process_stream_with_chunk_until(
+        resp,
+        move |body: DuckChatCompletion| {
+            let Some(message) = body.message else {
+                return ControlFlow::Continue(Vec::new());
+            };
+            let (text, cut) = reply
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(&message);
+            if cut {
+                ControlFlow::Break(text_delta(text))
+            } else {
+                ControlFlow::Continue(text_delta(text))
+            }
+        },
+        move |_| {
+            let mut reply = reply_end.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut events = text_delta(reply.finish());
+            let output_tokens = tokenizer::count_text(&model, reply.output.text());
+            // a cut reply differs from what the upstream session holds
+            if let (None, Some(hook)) = (reply.cut, on_complete) {
+                hook(reply.output.text().to_owned());
+            }
+            let (stop_reason, stop_sequence) = reply.stop_reason();
+            events.extend([
+                event(json!({"type": "content_block_stop", "index": 0})),
+                event(json!({
+                    "type": "message_delta",
+                    "delta": {"stop_reason": stop_reason, "stop_sequence": stop_sequence},
+                    "usage": {"output_tokens": output_tokens},
+                })),
+                event(json!({"type": "message_stop"})),
+            ]);
+            events
+        },
+        |err| vec![error_event(err)],
+    )
+    .flat_map(futures_util::stream::iter)
+}
+
+async fn single_response(
+    resp: reqwest::Response,
+    model: String,
+    input_tokens: u32,
+    output: OutputLimit,
+    on_complete: Option<CompletionHook>,
+) -> crate::Result<Response> {
+    let mut reply = Reply::new(output);
+    process_stream_until(resp, |body| {
+        if let Some(message) = body.message
+            && let (_, true) = reply.push(&message)
+        {
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    })
+    .await?;
+    reply.finish();
+    let text = reply.output.text();
+
+    // a cut reply differs from what the upstream session holds
+    if let (None, Some(hook)) = (reply.cut, on_complete) {
+        hook(text.to_owned());
+    }
+
+    let output_tokens = tokenizer::count_text(&model, text);
+    let (stop_reason, stop_sequence) = reply.stop_reason();
+    Ok(Json(MessageResponse {
+        id: &gen_id("msg"),
+        r#type: "message",
+        role: Role::Assistant,
+        model: &model,
+        content: vec![json!({"type": "text", "text": text})],
+        stop_reason: Some(stop_reason),
+        stop_sequence,
+        usage: AnthropicUsage {
+            input_tokens,
+            output_tokens,
+        },
+    })
+    .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Scenario;
+    use crate::mock::test_util::{spawn_proxy, spawn_proxy_with, sse_payloads};
+    use serde_json::Value;
+
+    #[test]
+    fn system_prompt_becomes_first_user_message() {
+        let mut req: MessagesRequest = serde_json::from_value(json!({
+            "model": "claude-3-haiku",
+            "max_tokens": 64,
+            "system": [{"type": "text", "text": "Be brief"}],
+            "messages": [
+                {"role": "user", "content": [
+                    {"type": "text", "text": "Hi"},
+                    {"type": "image", "source": {"type": "base64", "data": ""}},
+                ]},
+                {"role": "assistant", "content": "Hello"},
+            ],
+        }))
+        .unwrap();
+        let system = req.take_system();
+        let mut body = ChatRequest::from(req);
+        body.messages.splice(0..0, system);
+        assert_eq!(body.model, "claude-3-haiku");
+        assert_eq!(
+            serde_json::to_value(&body.messages).unwrap(),
+            json!([
+                {"role": "user", "content": "Be brief"},
+                {"role": "user", "content": [
+                    {"type": "text", "text": "Hi"},
+                    {"type": "image"},
+                ]},
+                {"role": "assistant", "content": "Hello"},
+            ])
+        );
+    }
+
+    async fn post_messages(addr: std::net::SocketAddr, body: Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("http://{addr}/v1/messages"))
+            .header(API_KEY_HEADER, "sk-test")
+            .json(&body)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn messages_single_response() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_messages(
+            addr,
+            json!({
+                "model": "claude-3-haiku",
+                "max_tokens": 64,
+                "messages": [{"role": "user", "content": "Hi"}],
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["type"], "message");
+        assert_eq!(body["role"], "assistant");
+        assert_eq!(body["content"][0]["text"], "Hello, world!");
+        assert_eq!(body["stop_reason"], "end_turn");
+    }
+
+    #[tokio::test]
+    async fn stream_opens_before_the_upstream_answers() {
+        let (addr, _) = spawn_proxy(Scenario::RateLimited.into()).await;
+        let resp = post_messages(
+            addr,
+            json!({
+                "model": "claude-3-haiku",
+                "max_tokens": 64,
+                "stream": true,
+                "messages": [{"role": "user", "content": "Hi"}],
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let body = resp.text().await.unwrap();
+        let events: Vec<Value> = sse_payloads(&body)
+            .into_iter()
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        assert_eq!(events[0]["type"], "message_start");
+        let last = events.last().unwrap();
+        assert_eq!(last["type"], "error");
+        assert_eq!(last["error"]["type"], "rate_limit_error");
+    }
+
+    #[tokio::test]
+    async fn disconnect_is_an_error() {
+        let (addr, _) = spawn_proxy(Scenario::Disconnect.into()).await;
+        let request = json!({
+            "model": "claude-3-haiku",
+            "max_tokens": 64,
+            "messages": [{"role": "user", "content": "Hi"}],
+        });
+        let resp = post_messages(addr, request.clone()).await;
+        assert_eq!(resp.status(), 502);
+
+        let mut request = request;
+        request["stream"] = json!(true);
+        let resp = post_messages(addr, request).await;
+        assert_eq!(resp.status(), 200);
+        let body = resp.text().await.unwrap();
+        let last: Value = serde_json::from_str(sse_payloads(&body).last().unwrap()).unwrap();
+        assert_eq!(last["type"], "error");
+        assert_eq!(last["error"]["type"], "api_error");
+        assert!(!body.contains("message_stop"));
+    }
+
+    #[tokio::test]
+    async fn unsupported_content_policy_applies() {
+        let (addr, mock) = spawn_proxy_with(Scenario::Normal.into(), |config| {
+            config.unsupported_content = crate::model::UnsupportedContent::Reject
+        })
+        .await;
+        let resp = post_messages(
+            addr,
+            json!({
+                "model": "claude-3-haiku",
+                "max_tokens": 64,
+                "system": "Be brief",
+                "messages": [{"role": "user", "content": [
+                    {"type": "text", "text": "Describe"},
+                    {"type": "image", "source": {"type": "base64", "data": ""}},
+                ]}],
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 400);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        let message = body["error"]["message"].as_str().unwrap();
+        assert!(
+            message.starts_with("messages[0].content[1].type: "),
+            "{message}"
+        );
+        assert!(mock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn messages_stream_events() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_messages(
+            addr,
+            json!({
+                "model": "claude-3-haiku",
+                "max_tokens": 64,
+                "stream": true,
+                "messages": [{"role": "user", "content": "Hi"}],
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let body = resp.text().await.unwrap();
+        let events: Vec<Value> = sse_payloads(&body)
+            .into_iter()
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        let types: Vec<&str> = events.iter().map(|e| e["type"].as_str().unwrap()).collect();
+        assert_eq!(types.first(), Some(&"message_start"));
+        assert_eq!(types[1], "content_block_start");
+        assert_eq!(
+            &types[types.len() - 3..],
+            ["content_block_stop", "message_delta", "message_stop"]
+        );
+        let text: String = events
+            .iter()
+            .filter(|e| e["type"] == "content_block_delta")
+            .map(|e| e["delta"]["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(text, "Hello, world!");
+        assert!(body.contains("event: message_start"));
+    }
+
+    #[tokio::test]
+    async fn limits_cut_the_reply_short() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let request = |stream, limits: Value| {
+            let mut body = json!({
+                "model": "claude-3-haiku",
+                "stream": stream,
+                "messages": [{"role": "user", "content": "Hi"}],
+            });
+            body.as_object_mut()
+                .unwrap()
+                .extend(limits.as_object().unwrap().clone());
+            post_messages(addr, body)
+        };
+
+        let body: Value = request(false, json!({"max_tokens": 1}))
+            .await
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(body["content"][0]["text"], "Hello");
+        assert_eq!(body["stop_reason"], "max_tokens");
+        assert_eq!(body["stop_sequence"], Value::Null);
+
+        let limits = json!({"max_tokens": 64, "stop_sequences": ["world"]});
+        let body: Value = request(false, limits.clone()).await.json().await.unwrap();
+        assert_eq!(body["content"][0]["text"], "Hello, ");
+        assert_eq!(body["stop_reason"], "stop_sequence");
+        assert_eq!(body["stop_sequence"], "world");
+
+        let body = request(true, limits).await.text().await.unwrap();
+        let events: Vec<Value> = sse_payloads(&body)
+            .into_iter()
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        let text: String = events
+            .iter()
+            .filter(|e| e["type"] == "content_block_delta")
+            .map(|e| e["delta"]["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(text, "Hello, ");
+        let delta = events
+            .iter()
+            .find(|e| e["type"] == "message_delta")
+            .unwrap();
+        assert_eq!(delta["delta"]["stop_reason"], "stop_sequence");
+        assert_eq!(delta["delta"]["stop_sequence"], "world");
+    }
+
+    #[tokio::test]
+    async fn messages_requires_api_key() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = reqwest::Client::new()
+            .post(format!("http://{addr}/v1/messages"))
+            .json(&json!({"model": "claude-3-haiku", "messages": []}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 401);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["type"], "error");
+        assert_eq!(body["error"]["type"], "authentication_error");
+        assert!(body["error"]["message"].is_string());
+
+        let resp = post_messages(
+            addr,
+            json!({"model": "unknown", "messages": [{"role": "user", "content": "Hi"}]}),
+        )
+        .await;
+        assert_eq!(resp.status(), 404);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["error"]["type"], "not_found_error");
+    }
+}
@@ -0,0 +1,191 @@
+//! API key authentication.
+//!
+//! Keys come from the `api_keys` table of the config, plus the legacy
+//! `api_key` as a key named `default`. Without any key, requests need no
+//! authentication.
+
+use crate::config::{ApiKey, ModelConfig};
+use crate::error::Error;
+use crate::model::now;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+/// Keys accepted by the proxy
+pub struct KeyTable {
+    keys: Vec<ApiKey>,
+}
+
+impl KeyTable {
+    pub fn new(api_key: Option<String>, api_keys: Vec<ApiKey>) -> Self {
+        let default = api_key.map(|key| ApiKey {
+            name: "default".to_owned(),
+            key: Some(key),
+            key_hash: None,
+            enabled: true,
+            models: Vec::new(),
+            expires_at: None,
+        });
+        Self {
+            keys: default.into_iter().chain(api_keys).collect(),
+        }
+    }
+
+    /// Entry of `key`, `None` when requests need no key. The name of the
+    /// entry is recorded on the `key` field of the current span.
+    pub fn authenticate(&self, key: Option<&str>) -> crate::Result<Option<&ApiKey>> {
+        if self.keys.is_empty() {
+            return Ok(None);
+        }
+        let key = key.ok_or(Error::InvalidApiKey)?;
+        // digests are compared in constant time, so timing tells nothing
+        // about how much of a key matched
+        let digest = Sha256::digest(key);
+        let entry = self
+            .keys
+            .iter()
+            .find(|entry| {
+                let plain = entry.key.iter().map(|key| Sha256::digest(key).to_vec());
+                let hashed = entry
+                    .key_hash
+                    .iter()
+                    .filter_map(|hash| hex::decode(hash).ok());
+                plain
+                    .chain(hashed)
+                    .any(|expected| expected.ct_eq(digest.as_slice()).into())
+            })
+            .ok_or(Error::InvalidApiKey)?;
+        if !entry.enabled {
+            tracing::info!("rejected disabled key '{}'", entry.name);
+            return Err(Error::InvalidApiKey);
+        }
+        if entry
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now())
+        {
+            tracing::info!("rejected expired key '{}'", entry.name);
+            return Err(Error::InvalidApiKey);
+        }
+        tracing::Span::current().record("key", entry.name.as_str());
+        Ok(Some(entry))
+    }
+}
+
+/// Whether `key` may use `model`
+pub fn allows(key: Option<&ApiKey>, model: &ModelConfig) -> bool {
+    key.is_none_or(|key| key.models.is_empty() || key.models.contains(&model.id))
+}
+
+/// Id under which requests made with `key` keep `id` in a shared store,
+/// out of reach of the other keys
+pub fn scoped_id(key: Option<&ApiKey>, id: &str) -> String {
+    match key {
+        // the length keeps names containing `:` apart
+        Some(key) => format!("{}:{}:{id}", key.name.len(), key.name),
+        None => id.to_owned(),
+    }
+}
+
+/// Check that `key` may use `model`, which is reported as not found otherwise
+pub fn check_model(key: Option<&ApiKey>, model: &ModelConfig) -> crate::Result<()> {
+    if allows(key, model) {
+        Ok(())
+    } else {
+        Err(Error::ModelNotFound(model.id.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::ModelRegistry;
+
+    fn entry(name: &str) -> ApiKey {
+        ApiKey {
+            name: name.to_owned(),
+            key: None,
+            key_hash: None,
+            enabled: true,
+            models: Vec::new(),
+            expires_at: None,
+        }
+    }
+
+    fn name(table: &KeyTable, key: &str) -> Option<String> {
+        table
+            .authenticate(Some(key))
+            .ok()
+            .flatten()
+            .map(|entry| entry.name.clone())
+    }
+
+    #[test]
+    fn empty_table_needs_no_key() {
+        let table = KeyTable::new(None, Vec::new());
+        assert!(matches!(table.authenticate(None), Ok(None)));
+    }
+
+    #[test]
+    fn keys_match_plain_or_hashed() {
+        let table = KeyTable::new(
+            Some("sk-legacy".to_owned()),
+            vec![
+                ApiKey {
+                    key: Some("sk-alice".to_owned()),
+                    ..entry("alice")
+                },
+                ApiKey {
+                    key_hash: Some(hex::encode(Sha256::digest("sk-ci")).to_uppercase()),
+                    ..entry("ci")
+                },
+            ],
+        );
+        assert_eq!(name(&table, "sk-legacy").as_deref(), Some("default"));
+        assert_eq!(name(&table, "sk-alice").as_deref(), Some("alice"));
+        assert_eq!(name(&table, "sk-ci").as_deref(), Some("ci"));
+        assert!(table.authenticate(Some("sk-other")).is_err());
+        assert!(table.authenticate(None).is_err());
+    }
+
+    #[test]
+    fn disabled_and_expired_keys_are_rejected() {
+        let table = KeyTable::new(
+            None,
+            vec![
+                ApiKey {
+                    key: Some("sk-disabled".to_owned()),
+                    enabled: false,
+                    ..entry("disabled")
+                },
+                ApiKey {
+                    key: Some("sk-expired".to_owned()),
+                    expires_at: Some(now() - 1),
+                    ..entry("expired")
+                },
+                ApiKey {
+                    key: Some("sk-valid".to_owned()),
+                    expires_at: Some(now() + 3600),
+                    ..entry("valid")
+                },
+            ],
+        );
+        assert!(table.authenticate(Some("sk-disabled")).is_err());
+        assert!(table.authenticate(Some("sk-expired")).is_err());
+        assert_eq!(name(&table, "sk-valid").as_deref(), Some("valid"));
+    }
+
+    #[test]
+    fn allowed_models_restrict_the_key() {
+        let key = ApiKey {
+            models: vec!["gpt-4o-mini".to_owned()],
+            ..entry("restricted")
+        };
+        let registry = ModelRegistry::default();
+        let model = |name| registry.resolve(name).unwrap();
+        assert!(check_model(Some(&key), model("gpt-4o-mini")).is_ok());
+        assert!(check_model(None, model("claude-3-haiku")).is_ok());
+        assert!(matches!(
+            check_model(Some(&key), model("claude-3-haiku")),
+            Err(Error::ModelNotFound(id)) if id == "claude-3-haiku"
+        ));
+    }
+}
@@ -1,8 +1,12 @@
+use crate::model::{MessageMode, UnsupportedContent};
 use serde::{Deserialize, Serialize};
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
     path::PathBuf,
 };
+use url::Url;
+
+const ORIGIN_API: &str = "https://duckduckgo.com";
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
@@ -27,8 +31,214 @@ pub struct Config {
     /// TLS private key file path (EC/PKCS8/RSA)
     pub tls_key: Option<PathBuf>,
 
-    /// Authentication Key
+    /// Authentication Key, accepted as a key named `default`
     pub api_key: Option<String>,
+
+    /// API keys of the users and services of the proxy
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+
+    /// Upstream chat API
+    #[serde(default)]
+    pub upstream: Upstream,
+
+    /// Default conversation history mode (compress/native)
+    #[serde(default)]
+    pub message_mode: MessageMode,
+
+    /// Conversation sessions
+    #[serde(default)]
+    pub sessions: Store,
+
+    /// Stored `/v1/responses` results for `previous_response_id`
+    #[serde(default)]
+    pub responses: Store,
+
+    /// Upstream retries when a JSON mode reply fails validation
+    #[serde(default = "default_json_retries")]
+    pub json_retries: u32,
+
+    /// Content parts the upstream can't take, such as images (drop/placeholder/reject)
+    #[serde(default)]
+    pub unsupported_content: UnsupportedContent,
+
+    /// Models served by the proxy
+    #[serde(default = "default_models")]
+    pub models: Vec<ModelConfig>,
+
+    /// Model serving requests for unknown models, which are rejected when unset
+    #[serde(default)]
+    pub default_model: Option<String>,
+
+    /// Interval of SSE comment heartbeats on streamed replies (seconds), 0 disables them
+    #[serde(default = "default_sse_keep_alive")]
+    pub sse_keep_alive: u64,
+}
+
+fn default_sse_keep_alive() -> u64 {
+    15
+}
+
+fn default_json_retries() -> u32 {
+    2
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Store {
+    /// Idle time before an entry expires (seconds)
+    pub ttl: u64,
+
+    /// Maximum number of entries kept, 0 disables the store
+    pub capacity: usize,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self {
+            ttl: 1800,
+            capacity: 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ModelConfig {
+    /// Public model id
+    pub id: String,
+
+    /// Model id sent upstream
+    pub upstream: String,
+
+    /// Owner reported in the model list
+    pub owned_by: String,
+
+    /// Creation time reported in the model list (unix seconds)
+    pub created: u64,
+
+    /// Context window (tokens)
+    pub context_window: u32,
+
+    /// Serve and list the model
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+
+    /// Other public names of the model
+    #[serde(default)]
+    pub aliases: Vec<String>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_models() -> Vec<ModelConfig> {
+    let model = |id: &str, upstream: &str, owned_by: &str, context_window| ModelConfig {
+        id: id.to_owned(),
+        upstream: upstream.to_owned(),
+        owned_by: owned_by.to_owned(),
+        created: 1686935002,
+        context_window,
+        enabled: true,
+        aliases: Vec::new(),
+    };
+    vec![
+        model("gpt-4o-mini", "gpt-4o-mini", "openai", 128_000),
+        model(
+            "claude-3-haiku",
+            "claude-3-haiku-20240307",
+            "claude",
+            200_000,
+        ),
+        model(
+            "llama-3.3-70b",
+            "meta-llama/Llama-3.3-70B-Instruct-Turbo",
+            "meta-llama",
+            128_000,
+        ),
+        model("o4-mini", "o4-mini", "openai", 200_000),
+        model(
+            "mixtral-small-3",
+            "mistralai/Mistral-Small-24B-Instruct-2501",
+            "mistral ai",
+            32_768,
+        ),
+    ]
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ApiKey {
+    /// Name of the key holder, attached to the request's tracing span
+    pub name: String,
+
+    /// Key in plain text
+    #[serde(default)]
+    pub key: Option<String>,
+
+    /// Hex SHA-256 of the key, in place of the plain key
+    #[serde(default)]
+    pub key_hash: Option<String>,
+
+    /// Accept the key
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+
+    /// Public ids of the models the key may use, all when empty
+    #[serde(default)]
+    pub models: Vec<String>,
+
+    /// Expiry time (unix seconds)
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Upstream {
+    /// Upstream base URL
+    pub base_url: Url,
+
+    /// Chat endpoint path
+    pub chat_path: String,
+
+    /// Status (token) endpoint path
+    pub status_path: String,
+
+    /// Origin header sent upstream
+    pub origin: String,
+
+    /// Referer header sent upstream
+    pub referer: String,
+}
+
+impl Upstream {
+    /// Full chat endpoint URL
+    pub fn chat_url(&self) -> Url {
+        self.join(&self.chat_path)
+    }
+
+    /// Full status endpoint URL
+    pub fn status_url(&self) -> Url {
+        self.join(&self.status_path)
+    }
+
+    /// Append `path` to the base URL, keeping any path prefix of the base
+    fn join(&self, path: &str) -> Url {
+        let mut url = self.base_url.clone();
+        let prefix = url.path().trim_end_matches('/').to_owned();
+        url.set_path(&format!("{prefix}/{}", path.trim_start_matches('/')));
+        url
+    }
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Self {
+            base_url: Url::parse(ORIGIN_API).expect("invalid default upstream url"),
+            chat_path: "/duckchat/v1/chat".to_owned(),
+            status_path: "/duckchat/v1/status".to_owned(),
+            origin: ORIGIN_API.to_owned(),
+            referer: ORIGIN_API.to_owned(),
+        }
+    }
 }
 
 impl Default for Config {
@@ -42,6 +252,16 @@ impl Default for Config {
             tls_cert: Default::default(),
             tls_key: Default::default(),
             api_key: Default::default(),
+            api_keys: Default::default(),
+            upstream: Default::default(),
+            message_mode: Default::default(),
+            sessions: Default::default(),
+            responses: Default::default(),
+            json_retries: default_json_retries(),
+            unsupported_content: Default::default(),
+            models: default_models(),
+            default_model: Default::default(),
+            sse_keep_alive: default_sse_keep_alive(),
         }
     }
 }
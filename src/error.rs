@@ -1,3 +1,5 @@
+use crate::model::DuckChatError;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error(transparent)]
@@ -24,9 +26,101 @@ pub enum Error {
     #[error("{0}")]
     BadRequest(String),
 
+    #[error("{message}")]
+    InvalidRequest {
+        message: String,
+        param: Option<String>,
+    },
+
+    #[error("The model `{0}` does not exist or you do not have access to it.")]
+    ModelNotFound(String),
+
+    #[error("upstream rate limit reached: {message}")]
+    UpstreamRateLimited {
+        message: String,
+        /// `Retry-After` of the upstream response
+        retry_after: Option<String>,
+    },
+
+    #[error("upstream unavailable: {message}")]
+    UpstreamUnavailable {
+        message: String,
+        /// `Retry-After` of the upstream response
+        retry_after: Option<String>,
+    },
+
+    #[error("upstream timed out")]
+    UpstreamTimeout,
+
+    #[error("upstream reply broke off: {0}")]
+    UpstreamInterrupted(String),
+
+    #[error("upstream rejected the request ({status}): {message}")]
+    ContentRejected {
+        status: reqwest::StatusCode,
+        message: String,
+    },
+
+    #[error("the reply did not match response_format after {attempts} attempts: {message}")]
+    ResponseFormat { attempts: u32, message: String },
+
     #[error("{0}")]
     HashError(&'static str),
 
     #[error("invalid api key")]
     InvalidApiKey,
 }
+
+impl Error {
+    /// Typed error of an upstream response failing with `status`
+    pub fn upstream(
+        status: reqwest::StatusCode,
+        headers: &reqwest::header::HeaderMap,
+        message: String,
+    ) -> Self {
+        use reqwest::StatusCode;
+
+        let retry_after = headers
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .map(ToOwned::to_owned);
+        match status {
+            StatusCode::TOO_MANY_REQUESTS => Error::UpstreamRateLimited {
+                message,
+                retry_after,
+            },
+            StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => Error::UpstreamTimeout,
+            status if status.is_client_error() => Error::ContentRejected { status, message },
+            _ => Error::UpstreamUnavailable {
+                message,
+                retry_after,
+            },
+        }
+    }
+
+    /// Typed error of an error event in the middle of an upstream reply
+    pub fn upstream_event(event: DuckChatError) -> Self {
+        let message = event.kind.unwrap_or_else(|| "upstream error".to_owned());
+        match event
+            .status
+            .and_then(|status| reqwest::StatusCode::from_u16(status).ok())
+        {
+            Some(status) => Error::upstream(status, &Default::default(), message),
+            None => Error::UpstreamInterrupted(message),
+        }
+    }
+
+    /// Typed error of a request that got no upstream response
+    pub fn transport(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            Error::UpstreamTimeout
+        } else if err.is_connect() {
+            Error::UpstreamUnavailable {
+                message: err.to_string(),
+                retry_after: None,
+            }
+        } else {
+            Error::RequestError(err)
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! JSON mode and structured outputs (`response_format`).
+//!
+//! The model is asked for JSON in a leading prompt; the JSON is then
+//! extracted from its reply and checked against the requested format.
+
+use crate::model::{ChatRequest, Content, Message, Role};
+use crate::schema;
+use serde::Deserialize;
+use serde_json::Value;
+
+// ==================== Request Body ====================
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchema },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct JsonSchema {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub schema: Option<Value>,
+}
+
+impl ChatRequest {
+    /// Requested JSON format, if the reply must be JSON
+    pub fn json_format(&self) -> Option<ResponseFormat> {
+        self.response_format
+            .clone()
+            .filter(|format| !matches!(format, ResponseFormat::Text))
+    }
+
+    /// Ask for a JSON reply in a leading user message
+    pub fn inject_format(&mut self) {
+        if let Some(format) = self.json_format() {
+            let prompt = Message::builder()
+                .role(Role::User)
+                .content(Content::Text(format.prompt()))
+                .build();
+            self.messages.insert(0, prompt);
+        }
+    }
+}
+
+impl ResponseFormat {
+    fn prompt(&self) -> String {
+        let mut prompt =
+            "Reply with only a valid JSON object, without code fences or any other text."
+                .to_owned();
+        if let ResponseFormat::JsonSchema { json_schema } = self {
+            prompt.push_str(&format!(
+                "\nThe object is `{}` and must match this JSON schema:",
+                json_schema.name
+            ));
+            if let Some(description) = &json_schema.description {
+                prompt.push_str(&format!("\n{description}"));
+            }
+            let schema = json_schema.schema.clone().unwrap_or_default();
+            prompt.push_str(&format!("\n{schema}"));
+        }
+        prompt
+    }
+
+    /// JSON text of `reply` if it satisfies the format, or why it doesn't
+    pub fn check(&self, reply: &str) -> Result<String, String> {
+        let (json, value) = extract_json(reply).ok_or("the reply is not valid JSON")?;
+        if !value.is_object() {
+            return Err("the reply is not a JSON object".to_owned());
+        }
+        if let ResponseFormat::JsonSchema { json_schema } = self
+            && let Some(schema) = &json_schema.schema
+        {
+            schema::validate(schema, &value)?;
+        }
+        Ok(json.to_owned())
+    }
+}
+
+/// JSON document in `reply`, which may be wrapped in a code fence or prose
+pub fn extract_json(reply: &str) -> Option<(&str, Value)> {
+    let reply = reply.trim();
+    let unfenced = reply
+        .strip_prefix("```json")
+        .or_else(|| reply.strip_prefix("```"))
+        .and_then(|fenced| fenced.strip_suffix("```"))
+        .map(str::trim);
+    let outermost = reply
+        .find(['{', '['])
+        .zip(reply.rfind(['}', ']']))
+        .and_then(|(start, end)| reply.get(start..=end));
+
+    [Some(reply), unfenced, outermost]
+        .into_iter()
+        .flatten()
+        .find_map(|json| Some((json, serde_json::from_str(json).ok()?)))
+}
+
+/// Message asking the model to fix a reply that failed `check`
+pub fn correction(error: &str) -> Message {
+    Message::builder()
+        .role(Role::User)
+        .content(Content::Text(format!(
+            "Your reply is invalid: {error}. Reply again with only the corrected JSON object."
+        )))
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn extract_json_from_fences_and_prose() {
+        assert_eq!(extract_json(r#" {"a": 1} "#).unwrap().0, r#"{"a": 1}"#);
+        assert_eq!(
+            extract_json("```json\n{\"a\": 1}\n```").unwrap().1,
+            json!({"a": 1})
+        );
+        assert_eq!(
+            extract_json("Sure! Here it is: {\"a\": [1]} Hope it helps.")
+                .unwrap()
+                .1,
+            json!({"a": [1]})
+        );
+        assert!(extract_json("no json here").is_none());
+    }
+
+    #[test]
+    fn check_validates_against_schema() {
+        let format: ResponseFormat = serde_json::from_value(json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": "answer",
+                "schema": {
+                    "type": "object",
+                    "properties": {"answer": {"type": "integer"}},
+                    "required": ["answer"],
+                },
+            },
+        }))
+        .unwrap();
+        assert_eq!(
+            format.check("```json\n{\"answer\": 42}\n```"),
+            Ok(r#"{"answer": 42}"#.to_owned())
+        );
+        assert_eq!(
+            format.check(r#"{"answer": "42"}"#),
+            Err("$.answer: expected integer, got string".to_owned())
+        );
+
+        let format: ResponseFormat =
+            serde_json::from_value(json!({"type": "json_object"})).unwrap();
+        assert!(format.check("[1, 2]").is_err());
+        assert!(format.check("Hello").is_err());
+    }
+}
@@ -5,13 +5,15 @@ use regex::Regex;
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 
-fn get_hex(s: &str) -> isize {
-    let t = if let Some(i) = s.chars().position(|c| c == '(') {
-        &s[i + 3..s.len() - 1]
-    } else {
-        s
+/// Value of a hex literal, possibly wrapped in an `_0x......(0x..)` call
+fn get_hex(s: &str) -> Result<isize> {
+    let t = match s.find('(') {
+        Some(i) => s
+            .get(i + 3..s.len() - 1)
+            .ok_or_else(|| HashError("truncated hex call"))?,
+        None => s,
     };
-    isize::from_str_radix(t, 16).unwrap()
+    isize::from_str_radix(t, 16).map_err(|_| HashError("invalid hex number"))
 }
 
 fn compute_sha256_base64(input: &str) -> String {
@@ -23,14 +25,15 @@ pub fn gen_request_hash(hash: &str) -> Result<String> {
     // let hash = "";
     let decoded_bytes = BASE64_STANDARD
         .decode(hash.as_bytes())
-        .expect("invalid base64");
-    let decoded_str = String::from_utf8(decoded_bytes).expect("invalid utf-8");
+        .map_err(|_| HashError("challenge is not valid base64"))?;
+    let decoded_str =
+        String::from_utf8(decoded_bytes).map_err(|_| HashError("challenge is not valid utf-8"))?;
     // let decoded_str = "".to_string();
     // dbg!(&decoded_str);
 
     let capture = |pat: &str| {
         Regex::new(pat)
-            .unwrap()
+            .ok()?
             .captures(&decoded_str)
             .and_then(|cap| cap.get(1))
             .map(|m| m.as_str())
@@ -41,6 +44,7 @@ pub fn gen_request_hash(hash: &str) -> Result<String> {
         .split(',')
         .map(|s| s.trim_matches('\''))
         .collect();
+    let array_len = string_array.len() as isize;
     // dbg!(&string_array);
 
     let offset = capture(r"0x([[:alnum:]]+);let").ok_or_else(|| HashError("offset not found"))?;
@@ -49,21 +53,25 @@ pub fn gen_request_hash(hash: &str) -> Result<String> {
 
     let mut shift_offset = None;
 
-    let find_offset = |pat, target: &'static str| {
-        let index = get_hex(pat);
+    let find_offset = |pat, target: &'static str| -> Result<isize> {
+        let index = get_hex(pat)?;
         // dbg!(&string_array);
         let origin_index = string_array
             .iter()
             .position(|&s| s == target)
-            .expect("offset pattern not found in string array") as isize;
-        origin_index - (index - offset)
+            .ok_or_else(|| HashError("offset pattern not found in string array"))?
+            as isize;
+        origin_index
+            .checked_sub(index)
+            .and_then(|n| n.checked_add(offset))
+            .ok_or(HashError("shift offset out of range"))
     };
 
     if shift_offset.is_none() {
         let promise_pat = capture(r"await Promise\[[^(]*\(0x([[:alnum:]]+)\)\]");
         // dbg!(promise_pat);
         if let Some(pat) = promise_pat {
-            shift_offset = Some(find_offset(pat, "all"));
+            shift_offset = Some(find_offset(pat, "all")?);
         }
     }
 
@@ -71,7 +79,7 @@ pub fn gen_request_hash(hash: &str) -> Result<String> {
         let user_agent_pat = capture(r"\]\(\[navigator\[[^(]*\(0x([[:alnum:]]+)\)\],");
         // dbg!(user_agent_pat);
         if let Some(pat) = user_agent_pat {
-            shift_offset = Some(find_offset(pat, "userAgent"));
+            shift_offset = Some(find_offset(pat, "userAgent")?);
         }
     }
 
@@ -79,7 +87,7 @@ pub fn gen_request_hash(hash: &str) -> Result<String> {
         let reduce_pat = capture(r"\(Number\)\[_0x.{6}\(0x(.*?)\)\]");
         // dbg!(reduce_pat);
         if let Some(pat) = reduce_pat {
-            shift_offset = Some(find_offset(pat, "reduce"));
+            shift_offset = Some(find_offset(pat, "reduce")?);
         }
     }
 
@@ -87,7 +95,7 @@ pub fn gen_request_hash(hash: &str) -> Result<String> {
         let query_pat = capture(r"\(0x([[:alnum:]]+)\)]\('\*'\)");
         // dbg!(query_pat);
         if let Some(pat) = query_pat {
-            shift_offset = Some(find_offset(pat, "querySelectorAll"));
+            shift_offset = Some(find_offset(pat, "querySelectorAll")?);
         }
     }
 
@@ -97,8 +105,8 @@ pub fn gen_request_hash(hash: &str) -> Result<String> {
     let mut server_hashes = vec![None, None, None];
 
     let server_hash_pats = Regex::new(r"'server_hashes':\[([^,]+),([^,]+),([^]]+)\]")
-        .unwrap()
-        .captures(&decoded_str)
+        .ok()
+        .and_then(|regex| regex.captures(&decoded_str))
         .and_then(|cap| {
             (1..=3)
                 .map(|i| cap.get(i).map(|m| m.as_str()))
@@ -107,19 +115,22 @@ pub fn gen_request_hash(hash: &str) -> Result<String> {
         .ok_or_else(|| HashError("server hash pats not found"))?;
     // dbg!(&server_hash_pats);
 
-    let resolve_value = |pat: &str| {
+    let resolve_value = |pat: &str| -> Result<String> {
         if pat.starts_with('\'') {
-            pat.trim_matches('\'').to_owned()
+            Ok(pat.trim_matches('\'').to_owned())
         } else {
-            let index = get_hex(pat);
-            let array_len = string_array.len() as isize;
-            let origin_index = (index - offset + shift_offset).rem_euclid(array_len);
-            string_array[origin_index as usize].to_owned()
+            let index = get_hex(pat)?;
+            let origin_index = index
+                .checked_sub(offset)
+                .and_then(|n| n.checked_add(shift_offset))
+                .ok_or(HashError("string index out of range"))?
+                .rem_euclid(array_len);
+            Ok(string_array[origin_index as usize].to_owned())
         }
     };
 
     for (i, pat) in server_hash_pats.iter().enumerate() {
-        server_hashes[i] = Some(resolve_value(pat));
+        server_hashes[i] = Some(resolve_value(pat)?);
     }
     // dbg!(&server_hashes);
 
@@ -129,7 +140,7 @@ pub fn gen_request_hash(hash: &str) -> Result<String> {
         // dbg!("innerHTML check");
         let innerhtml_pat = capture(r"=([^,;]+),String")
             .ok_or_else(|| HashError("inner html pattern not found"))?;
-        let innerhtml = resolve_value(innerhtml_pat);
+        let innerhtml = resolve_value(innerhtml_pat)?;
         // dbg!(&innerhtml);
         let inner_html_data: HashMap<&str, isize> = HashMap::from([
             ("<div><div></div><div></div", 99),
@@ -142,38 +153,47 @@ pub fn gen_request_hash(hash: &str) -> Result<String> {
             .ok_or_else(|| HashError("unknown inner html pattern"))?;
         let number_pat = capture(r"String\(0x([[:alnum:]]+)\+")
             .ok_or_else(|| HashError("extracted number not found"))?;
-        let number = get_hex(number_pat);
-        compute_sha256_base64(&(number + inner_html_len).to_string())
+        let number = get_hex(number_pat)?;
+        let number = number
+            .checked_add(*inner_html_len)
+            .ok_or(HashError("extracted number out of range"))?;
+        compute_sha256_base64(&number.to_string())
     } else if decoded_str.contains("instanceof HTMLDivElement") {
         // dbg!("13 checks");
         let number_pat = capture(r",0x([[:alnum:]]+)\)\);\}\(\)\),\(function")
             .ok_or_else(|| HashError("extracted number not found"))?;
-        let number = get_hex(number_pat);
-        compute_sha256_base64(&(number + 12).to_string())
+        let number = get_hex(number_pat)?;
+        let number = number
+            .checked_add(12)
+            .ok_or(HashError("extracted number out of range"))?;
+        compute_sha256_base64(&number.to_string())
     } else if decoded_str.contains("Content-Security-Policy") {
         // dbg!("4 checks");
         let number_pat = capture(r",0x([[:alnum:]]+)\)\);\}\(\)\),\(function")
             .ok_or_else(|| HashError("extracted number not found"))?;
-        let number = get_hex(number_pat);
-        compute_sha256_base64(&(number + 4).to_string())
+        let number = get_hex(number_pat)?;
+        let number = number
+            .checked_add(4)
+            .ok_or(HashError("extracted number out of range"))?;
+        compute_sha256_base64(&number.to_string())
     } else {
         return Err(HashError("unknown second client hash"));
     };
 
     let third_pat = capture(r",0x([^)]+)\)\);}.....,'signals'")
         .ok_or_else(|| HashError("third pattern not found"))?;
-    let third_num = get_hex(third_pat);
+    let third_num = get_hex(third_pat)?;
     let third_hash = compute_sha256_base64(&third_num.to_string());
     // dbg!(third_num);
 
     let challenge_id_pat =
         capture(r"'challenge_id':([^},]+)").ok_or_else(|| HashError("challenge id not found"))?;
-    let challenge_id = resolve_value(challenge_id_pat);
+    let challenge_id = resolve_value(challenge_id_pat)?;
     // dbg!(&challenge_id);
 
     let timestamp_pat =
         capture(r"'timestamp':([^},]+)").ok_or_else(|| HashError("timestamp not found"))?;
-    let timestamp = resolve_value(timestamp_pat);
+    let timestamp = resolve_value(timestamp_pat)?;
     // dbg!(&timestamp);
 
     let result_json = serde_json::json!({
@@ -193,3 +213,70 @@ pub fn gen_request_hash(hash: &str) -> Result<String> {
 
     Ok(BASE64_STANDARD.encode(result_json.to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::challenge;
+
+    fn hash_error(challenge: &str) -> &'static str {
+        match gen_request_hash(challenge) {
+            Err(HashError(stage)) => stage,
+            result => panic!("expected a hash error, got {result:?}"),
+        }
+    }
+
+    fn encoded(script: &str) -> String {
+        BASE64_STANDARD.encode(script)
+    }
+
+    #[test]
+    fn get_hex_rejects_malformed_numbers() {
+        assert_eq!(get_hex("1f").unwrap(), 31);
+        assert_eq!(get_hex("_0x1234(0x1f)").unwrap(), 31);
+        assert!(get_hex("zz").is_err());
+        assert!(get_hex("(").is_err());
+        assert!(get_hex("ffffffffffffffffffff").is_err());
+    }
+
+    #[test]
+    fn malformed_challenges_are_errors() {
+        assert_eq!(hash_error("not base64!"), "challenge is not valid base64");
+        assert_eq!(
+            hash_error(&BASE64_STANDARD.encode([0xff, 0xfe])),
+            "challenge is not valid utf-8"
+        );
+        assert_eq!(hash_error(&encoded("")), "string array not found");
+        assert_eq!(
+            hash_error(&encoded("const _0xa1b2c3=['all'];")),
+            "offset not found"
+        );
+        assert_eq!(
+            hash_error(&encoded("const _0xa1b2c3=['all'];_0x1=0x0;let")),
+            "shift offset not found"
+        );
+        assert_eq!(
+            hash_error(&encoded(
+                "const _0xa1b2c3=['mock'];_0x1=0x0;let await Promise[_0xa1b2c3(0x0)]"
+            )),
+            "offset pattern not found in string array"
+        );
+        assert_eq!(
+            hash_error(&encoded(
+                "const _0xa1b2c3=['x','all'];_0x1=0x0;let await Promise[_0xa1b2c3(0x0)] \
+                 'server_hashes':[_0xa1b2c3(0x7fffffffffffffff),'b','c']"
+            )),
+            "string index out of range"
+        );
+    }
+
+    #[test]
+    fn truncated_challenge_is_an_error() {
+        let script = String::from_utf8(BASE64_STANDARD.decode(challenge()).unwrap()).unwrap();
+        // the timestamp, the last pattern read, may itself be cut short
+        let end = script.find("'timestamp'").unwrap();
+        for len in 0..end {
+            assert!(gen_request_hash(&encoded(&script[..len])).is_err());
+        }
+    }
+}
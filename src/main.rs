@@ -1,15 +1,26 @@
+mod anthropic;
+mod auth;
 mod client;
 mod config;
 mod error;
+mod format;
 mod hash;
+mod mock;
 mod model;
+mod ollama;
 mod process;
+mod responses;
 mod route;
+mod schema;
 mod serve;
+mod session;
+mod store;
+mod tokenizer;
+mod tools;
 
 use argh::FromArgs;
 pub use error::Error;
-use std::path::PathBuf;
+use std::{net::SocketAddr, path::PathBuf};
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -29,6 +40,8 @@ pub enum Commands {
     Run(RunCommand),
     /// Generate config template file (yaml format file)
     GT(GTCommand),
+    /// Run a mock upstream for offline testing
+    MockUpstream(MockUpstreamCommand),
 }
 
 #[derive(FromArgs, Debug)]
@@ -49,10 +62,25 @@ pub struct GTCommand {
     pub config_path: PathBuf,
 }
 
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "mock-upstream")]
+/// Arguments for mock-upstream command
+pub struct MockUpstreamCommand {
+    /// bind address
+    #[argh(option, default = "SocketAddr::from(([127, 0, 0, 1], 8081))")]
+    pub bind: SocketAddr,
+
+    /// default scenario (normal, malformed, disconnect, error, rate-limited,
+    /// reject-history)
+    #[argh(option, default = "mock::Scenario::Normal")]
+    pub scenario: mock::Scenario,
+}
+
 fn main() -> Result<()> {
     let opt: Opt = argh::from_env();
     match opt.commands {
         Commands::Run(args) => serve::run(args.config_path),
         Commands::GT(args) => config::generate_template(args.config_path),
+        Commands::MockUpstream(args) => mock::run(args.bind, args.scenario),
     }
 }
@@ -0,0 +1,529 @@
+//! Mock DuckChat upstream serving scripted responses, used by the
+//! `mock-upstream` command and by the test suite.
+
+use crate::Result;
+use axum::{
+    Router,
+    body::{Body, Bytes},
+    extract::State,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64_STANDARD};
+use serde_json::Value;
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::net::TcpListener;
+
+/// Header selecting a scenario for a single chat request
+pub const SCENARIO_HEADER: &str = "x-mock-scenario";
+
+/// Model name reported in mock completion frames
+const MOCK_MODEL: &str = "gpt-4o-mini-2024-07-18";
+
+/// Minimal challenge script accepted by `hash::gen_request_hash`
+const CHALLENGE: &str = "const _0xa1b2c3=['all','userAgent','mock'];\
+(function(_0x1,_0x2){_0x1=_0x1-0x0;let _0x3=_0x2;}());\
+(async function(){await Promise[_0xa1b2c3(0x0)]([]);\
+'Content-Security-Policy';(function(){return _0x4(0x1,0x4));}()),(function(){\
+return _0x5(0x2,0x9));}abcde,'signals':{},\
+'server_hashes':['mock-a','mock-b','mock-c'],\
+'challenge_id':'mock-challenge','timestamp':'0'})();";
+
+/// One SSE frame, or a connection-level event, sent by the mock chat endpoint
+#[derive(Clone, Debug)]
+pub enum Frame {
+    /// A `DuckChatCompletion` frame carrying a message
+    Message(String),
+    /// A raw `data:` payload, sent verbatim
+    Raw(String),
+    /// The `[DONE]` terminator
+    Done,
+    /// Abort the connection without terminating the stream
+    Disconnect,
+    /// Keep the connection open without sending more, setting the flag once
+    /// the client goes away
+    #[cfg(test)]
+    Stall(Arc<std::sync::atomic::AtomicBool>),
+}
+
+/// Sets its flag when dropped
+#[cfg(test)]
+struct SetOnDrop(Arc<std::sync::atomic::AtomicBool>);
+
+#[cfg(test)]
+impl Drop for SetOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Scripted chat response
+#[derive(Clone, Debug)]
+pub struct Script {
+    /// Response status code
+    pub status: StatusCode,
+    /// Frames sent when the status is successful
+    pub frames: Vec<Frame>,
+    /// Reject requests carrying more messages than this with HTTP 400
+    pub max_messages: Option<usize>,
+    /// Script of the following requests
+    pub next: Option<Box<Script>>,
+    /// Base64 challenge served by the status endpoint instead of [`challenge`]
+    pub challenge: Option<String>,
+}
+
+impl Script {
+    /// Successful stream of `messages` terminated by `[DONE]`
+    pub fn messages<I, S>(messages: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut frames: Vec<Frame> = messages
+            .into_iter()
+            .map(|m| Frame::Message(m.into()))
+            .collect();
+        frames.push(Frame::Done);
+        Self {
+            status: StatusCode::OK,
+            frames,
+            max_messages: None,
+            next: None,
+            challenge: None,
+        }
+    }
+
+    /// Upstream error response with `status`
+    pub fn error(status: StatusCode) -> Self {
+        Self {
+            status,
+            frames: Vec::new(),
+            max_messages: None,
+            next: None,
+            challenge: None,
+        }
+    }
+
+    /// Reject requests carrying more than `max` messages
+    pub fn max_messages(mut self, max: usize) -> Self {
+        self.max_messages = Some(max);
+        self
+    }
+
+    /// Serve `challenge` from the status endpoint
+    #[cfg(test)]
+    pub fn challenge(mut self, challenge: impl Into<String>) -> Self {
+        self.challenge = Some(challenge.into());
+        self
+    }
+
+    /// Serve this script once, then `next` for the following requests
+    #[cfg(test)]
+    pub fn then(mut self, next: Script) -> Self {
+        let next = match self.next.take() {
+            Some(tail) => tail.then(next),
+            None => next,
+        };
+        self.next = Some(Box::new(next));
+        self
+    }
+
+    /// Script of the `n`th request, counting from zero
+    fn nth(&self, n: usize) -> &Script {
+        let mut script = self;
+        for _ in 0..n {
+            match &script.next {
+                Some(next) => script = next,
+                None => break,
+            }
+        }
+        script
+    }
+}
+
+/// Built-in scenarios selectable from the command line or per request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scenario {
+    /// A short answer terminated by `[DONE]`
+    Normal,
+    /// A malformed JSON frame in the middle of the answer
+    Malformed,
+    /// The connection drops before `[DONE]`
+    Disconnect,
+    /// The upstream replies with HTTP 500
+    Error,
+    /// The upstream replies with HTTP 429
+    RateLimited,
+    /// The upstream rejects requests carrying more than one message
+    RejectHistory,
+}
+
+impl FromStr for Scenario {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(Scenario::Normal),
+            "malformed" => Ok(Scenario::Malformed),
+            "disconnect" => Ok(Scenario::Disconnect),
+            "error" => Ok(Scenario::Error),
+            "rate-limited" => Ok(Scenario::RateLimited),
+            "reject-history" => Ok(Scenario::RejectHistory),
+            _ => Err(format!("unknown scenario: {s}")),
+        }
+    }
+}
+
+impl From<Scenario> for Script {
+    fn from(scenario: Scenario) -> Self {
+        match scenario {
+            Scenario::Normal => Script::messages(["Hello", ", ", "world", "!"]),
+            Scenario::Malformed => Script {
+                status: StatusCode::OK,
+                frames: vec![
+                    Frame::Message("Hello".to_owned()),
+                    Frame::Raw("{\"message\":".to_owned()),
+                    Frame::Message(", world".to_owned()),
+                    Frame::Done,
+                ],
+                max_messages: None,
+                next: None,
+                challenge: None,
+            },
+            Scenario::Disconnect => Script {
+                status: StatusCode::OK,
+                frames: vec![
+                    Frame::Message("Hello".to_owned()),
+                    Frame::Message(", wor".to_owned()),
+                    Frame::Disconnect,
+                ],
+                max_messages: None,
+                next: None,
+                challenge: None,
+            },
+            Scenario::Error => Script::error(StatusCode::INTERNAL_SERVER_ERROR),
+            Scenario::RateLimited => Script::error(StatusCode::TOO_MANY_REQUESTS),
+            Scenario::RejectHistory => Script::from(Scenario::Normal).max_messages(1),
+        }
+    }
+}
+
+/// Base64 encoded challenge returned by the status endpoint
+pub fn challenge() -> String {
+    BASE64_STANDARD.encode(CHALLENGE)
+}
+
+/// Chat request received by the mock upstream
+#[derive(Clone, Debug)]
+pub struct MockRequest {
+    /// `x-vqd-hash-1` request header
+    pub hash: String,
+    /// JSON request body
+    pub body: Value,
+}
+
+/// Chat requests received by the mock upstream
+pub type RequestLog = Arc<Mutex<Vec<MockRequest>>>;
+
+#[derive(Clone)]
+struct MockState {
+    script: Arc<Script>,
+    requests: Option<RequestLog>,
+    count: Arc<AtomicUsize>,
+}
+
+/// Build the mock upstream router serving `script` by default, recording
+/// chat requests into `requests` when given
+pub fn router(script: Script, requests: Option<RequestLog>) -> Router {
+    Router::new()
+        .route("/duckchat/v1/status", get(status))
+        .route("/duckchat/v1/chat", post(chat))
+        .with_state(MockState {
+            script: Arc::new(script),
+            requests,
+            count: Default::default(),
+        })
+}
+
+/// Running mock upstream
+#[cfg(test)]
+pub struct MockUpstream {
+    pub addr: SocketAddr,
+    requests: RequestLog,
+}
+
+#[cfg(test)]
+impl MockUpstream {
+    /// Base URL of the mock upstream
+    pub fn base_url(&self) -> url::Url {
+        format!("http://{}", self.addr)
+            .parse()
+            .expect("invalid mock url")
+    }
+
+    /// Chat requests received so far
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Serve the mock upstream on `bind`
+#[cfg(test)]
+pub async fn spawn(bind: SocketAddr, script: Script) -> Result<MockUpstream> {
+    let listener = TcpListener::bind(bind).await?;
+    let addr = listener.local_addr()?;
+    let requests = RequestLog::default();
+    let router = router(script, Some(requests.clone()));
+    tokio::spawn(async move {
+        if let Err(err) = axum::serve(listener, router).await {
+            tracing::warn!("mock upstream stopped: {err}");
+        }
+    });
+    Ok(MockUpstream { addr, requests })
+}
+
+#[tokio::main]
+pub async fn run(bind: SocketAddr, scenario: Scenario) -> Result<()> {
+    crate::serve::init_logger(true)?;
+    let listener = TcpListener::bind(bind).await?;
+    tracing::info!("Mock upstream address: {}", listener.local_addr()?);
+    tracing::info!("Default scenario: {scenario:?}");
+    axum::serve(listener, router(scenario.into(), None))
+        .await
+        .map_err(Into::into)
+}
+
+async fn status(State(state): State<MockState>) -> Response {
+    let challenge = state.script.challenge.clone().unwrap_or_else(challenge);
+    ([("x-vqd-hash-1", challenge)], StatusCode::OK).into_response()
+}
+
+async fn chat(State(state): State<MockState>, headers: HeaderMap, body: Bytes) -> Response {
+    let Some(hash) = headers
+        .get("x-vqd-hash-1")
+        .and_then(|value| value.to_str().ok())
+    else {
+        return (StatusCode::BAD_REQUEST, "missing x-vqd-hash-1").into_response();
+    };
+
+    let body = serde_json::from_slice::<Value>(&body).unwrap_or_default();
+    let count = state.count.fetch_add(1, Ordering::Relaxed) + 1;
+    let request = MockRequest {
+        hash: hash.to_owned(),
+        body: body.clone(),
+    };
+    tracing::debug!("chat request #{count} ({}): {}", request.hash, request.body);
+    if let Some(requests) = &state.requests {
+        requests.lock().unwrap().push(request);
+    }
+
+    let script = match headers
+        .get(SCENARIO_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(Scenario::from_str)
+    {
+        Some(Ok(scenario)) => scenario.into(),
+        Some(Err(err)) => return (StatusCode::BAD_REQUEST, err).into_response(),
+        None => state.script.nth(count - 1).clone(),
+    };
+
+    let messages = body["messages"].as_array().map_or(0, Vec::len);
+    if script.max_messages.is_some_and(|max| messages > max) {
+        let body = serde_json::json!({
+            "action": "error",
+            "status": 400,
+            "type": "ERR_INVALID_MESSAGES",
+        });
+        return (StatusCode::BAD_REQUEST, body.to_string()).into_response();
+    }
+
+    if !script.status.is_success() {
+        let body = serde_json::json!({
+            "action": "error",
+            "status": script.status.as_u16(),
+            "type": "ERR_MOCK",
+        });
+        let mut resp = (script.status, body.to_string()).into_response();
+        if script.status == StatusCode::TOO_MANY_REQUESTS {
+            resp.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from_static("1"));
+        }
+        return resp;
+    }
+
+    let body = Body::from_stream(async_stream::stream! {
+        for frame in script.frames {
+            match frame {
+                Frame::Message(message) => yield Ok(sse_data(
+                    &serde_json::json!({
+                        "role": "assistant",
+                        "message": message,
+                        "created": 1_700_000_000u64,
+                        "id": "mock-completion",
+                        "action": "success",
+                        "model": MOCK_MODEL,
+                    })
+                    .to_string(),
+                )),
+                Frame::Raw(data) => yield Ok(sse_data(&data)),
+                Frame::Done => yield Ok(sse_data("[DONE]")),
+                Frame::Disconnect => {
+                    // let the frames sent so far reach the client first
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    yield Err(std::io::Error::other("mock disconnect"));
+                }
+                #[cfg(test)]
+                Frame::Stall(closed) => {
+                    let _closed = SetOnDrop(closed);
+                    std::future::pending::<()>().await;
+                }
+            }
+        }
+    });
+
+    (
+        [
+            (
+                header::CONTENT_TYPE.as_str(),
+                "text/event-stream".to_owned(),
+            ),
+            ("x-vqd-hash-1", format!("mock-next-{count}")),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+fn sse_data(data: &str) -> String {
+    format!("data: {data}\n\n")
+}
+
+/// Test helpers for driving the mock upstream
+#[cfg(test)]
+pub mod test_util {
+    use super::*;
+    use crate::config::Config;
+
+    /// Spawn a mock upstream on an ephemeral port
+    pub async fn spawn_local(script: Script) -> MockUpstream {
+        spawn(SocketAddr::from(([127, 0, 0, 1], 0)), script)
+            .await
+            .expect("failed to spawn mock upstream")
+    }
+
+    /// Serve the proxy against a mock upstream
+    pub async fn spawn_proxy(script: Script) -> (SocketAddr, MockUpstream) {
+        spawn_proxy_with(script, |_| {}).await
+    }
+
+    /// Serve the proxy against a mock upstream, adjusting the configuration
+    /// with `configure`. Requests are authenticated with `sk-test`.
+    pub async fn spawn_proxy_with<F>(script: Script, configure: F) -> (SocketAddr, MockUpstream)
+    where
+        F: FnOnce(&mut Config),
+    {
+        let mock = spawn_local(script).await;
+        let mut config = Config {
+            timeout: 10,
+            connect_timeout: 10,
+            tcp_keepalive: None,
+            api_key: Some("sk-test".to_owned()),
+            ..Default::default()
+        };
+        config.upstream.base_url = mock.base_url();
+        configure(&mut config);
+
+        let state = crate::serve::app_state(&config).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, crate::serve::router(state)).await });
+        (addr, mock)
+    }
+
+    /// POST `body` to the proxy at `path`
+    pub async fn post_json(addr: SocketAddr, path: &str, body: Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("http://{addr}{path}"))
+            .bearer_auth("sk-test")
+            .json(&body)
+            .send()
+            .await
+            .expect("failed to reach proxy")
+    }
+
+    /// Upstream chat response produced by `script`
+    pub async fn upstream_response(script: Script) -> reqwest::Response {
+        let addr = spawn_local(script).await.addr;
+        reqwest::Client::new()
+            .post(format!("http://{addr}/duckchat/v1/chat"))
+            .header("x-vqd-hash-1", "test")
+            .send()
+            .await
+            .expect("failed to reach mock upstream")
+    }
+
+    /// Collect a response body into a string
+    pub async fn body_text(resp: Response) -> String {
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .expect("failed to read body");
+        String::from_utf8(bytes.to_vec()).expect("body is not utf-8")
+    }
+
+    /// Data payloads of every SSE event in `body`
+    pub fn sse_payloads(body: &str) -> Vec<&str> {
+        body.lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge_is_accepted_by_hash_generator() {
+        assert!(crate::hash::gen_request_hash(&challenge()).is_ok());
+    }
+
+    #[test]
+    fn scenario_from_str() {
+        assert_eq!("normal".parse(), Ok(Scenario::Normal));
+        assert_eq!("rate-limited".parse(), Ok(Scenario::RateLimited));
+        assert!("unknown".parse::<Scenario>().is_err());
+    }
+
+    #[tokio::test]
+    async fn chat_requires_hash_header() {
+        let addr = test_util::spawn_local(Scenario::Normal.into()).await.addr;
+        let resp = reqwest::Client::new()
+            .post(format!("http://{addr}/duckchat/v1/chat"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn chat_scenario_header_overrides_script() {
+        let addr = test_util::spawn_local(Scenario::Normal.into()).await.addr;
+        let resp = reqwest::Client::new()
+            .post(format!("http://{addr}/duckchat/v1/chat"))
+            .header("x-vqd-hash-1", "test")
+            .header(SCENARIO_HEADER, "rate-limited")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -1,63 +1,389 @@
+use crate::config::{Config, ModelConfig};
+use crate::format::ResponseFormat;
+use crate::tools::{self, FunctionCall, Tool, ToolCall, ToolChoice};
 use serde::{Deserialize, Deserializer, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 use typed_builder::TypedBuilder;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
     System,
     Assistant,
     User,
+    /// Instructions replacing `system` for newer models
+    Developer,
+    /// Tool results, sent upstream as user messages
+    Tool,
+    /// Legacy function results, sent upstream as user messages
+    Function,
+}
+
+impl Role {
+    /// Role accepted by the upstream, which has no system or tool role
+    pub fn upstream(self) -> Role {
+        match self {
+            Role::System | Role::Developer | Role::Tool | Role::Function => Role::User,
+            role => role,
+        }
+    }
+}
+
+/// Models served by the proxy, looked up by public id, alias or upstream id
+#[derive(Debug, Clone)]
+pub struct ModelRegistry {
+    models: Vec<ModelConfig>,
+}
+
+impl ModelRegistry {
+    pub fn new(models: Vec<ModelConfig>) -> Self {
+        Self { models }
+    }
+
+    /// Enabled models, in configuration order
+    pub fn enabled(&self) -> impl Iterator<Item = &ModelConfig> {
+        self.models.iter().filter(|model| model.enabled)
+    }
+
+    /// Enabled model named `name`
+    pub fn resolve(&self, name: &str) -> Option<&ModelConfig> {
+        self.enabled().find(|model| {
+            model.id == name || model.upstream == name || model.aliases.iter().any(|a| a == name)
+        })
+    }
+}
+
+impl Default for ModelRegistry {
+    fn default() -> Self {
+        Self::new(Config::default().models)
+    }
 }
 
 // ==================== Request Body ====================
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, TypedBuilder)]
 pub struct ChatRequest {
-    #[serde(deserialize_with = "deserialize_model")]
+    /// Model as requested, the upstream id once resolved
+    #[builder(setter(transform = |model: &str| model.to_owned()))]
     pub model: String,
     #[serde(deserialize_with = "deserialize_message")]
     pub messages: Vec<Message>,
     #[serde(skip_serializing, default)]
+    #[builder(default, setter(into))]
     pub stream: Option<bool>,
     #[serde(skip_serializing, default)]
+    #[builder(default)]
     pub compressed: bool,
+    /// How the conversation history is sent upstream, overriding the server default
+    #[serde(skip_serializing, default)]
+    #[builder(default)]
+    pub message_mode: Option<MessageMode>,
+    #[serde(skip_serializing, default)]
+    #[builder(default)]
+    pub user: Option<String>,
+    #[serde(skip_serializing, default)]
+    #[builder(default)]
+    pub stream_options: Option<StreamOptions>,
+    #[serde(skip_serializing, default)]
+    #[builder(default)]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing, default)]
+    #[builder(default)]
+    pub max_completion_tokens: Option<u32>,
+    #[serde(skip_serializing, default)]
+    #[builder(default)]
+    pub stop: Option<Stop>,
+    #[serde(skip_serializing, default)]
+    #[builder(default)]
+    pub tools: Vec<Tool>,
+    #[serde(skip_serializing, default)]
+    #[builder(default)]
+    pub tool_choice: Option<ToolChoice>,
+    #[serde(skip_serializing, default)]
+    #[builder(default)]
+    pub response_format: Option<ResponseFormat>,
+    /// Number of choices, each from its own upstream call
+    #[serde(skip_serializing, default)]
+    #[builder(default)]
+    pub n: Option<u32>,
+}
+
+/// Most choices a request may ask for, as on OpenAI
+pub const MAX_CHOICES: u32 = 128;
+
+/// Sequences ending the reply, one or a list
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct StreamOptions {
+    /// Send a final chunk with the usage of the whole request
+    #[serde(default)]
+    pub include_usage: bool,
+}
+
+impl ChatRequest {
+    /// Whether a streamed response ends with a usage chunk
+    pub fn include_usage(&self) -> bool {
+        self.stream_options
+            .is_some_and(|options| options.include_usage)
+    }
+
+    /// Reply token budget, `max_completion_tokens` superseding the deprecated `max_tokens`
+    pub fn output_limit(&self) -> Option<u32> {
+        self.max_completion_tokens.or(self.max_tokens)
+    }
+
+    /// Non-empty stop sequences of the request
+    pub fn stop_sequences(&self) -> Vec<String> {
+        let stop = match &self.stop {
+            Some(Stop::One(stop)) => std::slice::from_ref(stop),
+            Some(Stop::Many(stop)) => stop.as_slice(),
+            None => &[],
+        };
+        stop.iter()
+            .filter(|stop| !stop.is_empty())
+            .cloned()
+            .collect()
+    }
+
+    /// Number of choices to generate, validating `n`
+    pub fn choice_count(&self) -> crate::Result<usize> {
+        match self.n.unwrap_or(1) {
+            n @ 1..=MAX_CHOICES => Ok(n as usize),
+            _ => Err(crate::Error::InvalidRequest {
+                message: format!("n must be between 1 and {MAX_CHOICES}"),
+                param: Some("n".to_owned()),
+            }),
+        }
+    }
+
+    /// Replace the model with its upstream id from `registry`, falling back
+    /// to `default` for unknown models. Returns the entry of the model.
+    pub fn resolve_model<'r>(
+        &mut self,
+        registry: &'r ModelRegistry,
+        default: Option<&str>,
+    ) -> crate::Result<&'r ModelConfig> {
+        let model = registry
+            .resolve(&self.model)
+            .or_else(|| {
+                let model = registry.resolve(default?)?;
+                tracing::debug!("unknown model {}, using {}", self.model, model.id);
+                Some(model)
+            })
+            .ok_or_else(|| crate::Error::ModelNotFound(self.model.clone()))?;
+        self.model = model.upstream.clone();
+        Ok(model)
+    }
+
+    /// Apply `policy` to content parts the upstream can't take
+    pub fn resolve_unsupported(&mut self, policy: UnsupportedContent) -> crate::Result<()> {
+        for (i, message) in self.messages.iter_mut().enumerate() {
+            let Some(Content::Vec(items)) = &mut message.content else {
+                continue;
+            };
+            let mut resolved = Vec::with_capacity(items.len());
+            for (j, item) in items.drain(..).enumerate() {
+                if let Some(text) = item.as_text() {
+                    resolved.push(ContentItem::text(text));
+                    continue;
+                }
+                match policy {
+                    UnsupportedContent::Drop => {}
+                    UnsupportedContent::Placeholder => {
+                        resolved.push(ContentItem::text(item.placeholder()))
+                    }
+                    UnsupportedContent::Reject => {
+                        return Err(crate::Error::InvalidRequest {
+                            message: format!(
+                                "content parts of type '{}' are not supported",
+                                item.r#type
+                            ),
+                            param: Some(format!("messages[{i}].content[{j}].type")),
+                        });
+                    }
+                }
+            }
+            *items = resolved;
+        }
+        Ok(())
+    }
+}
+
+/// Legacy text completion request
+#[derive(Debug, Deserialize)]
+pub struct CompletionRequest {
+    pub model: String,
+    pub prompt: Prompt,
+    #[serde(default)]
+    pub stream: Option<bool>,
+    #[serde(default)]
+    pub stream_options: Option<StreamOptions>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub stop: Option<Stop>,
+    #[serde(default)]
+    pub n: Option<u32>,
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Prompt {
+    Text(String),
+    Batch(Vec<String>),
+}
+
+impl TryFrom<CompletionRequest> for ChatRequest {
+    type Error = crate::Error;
+
+    fn try_from(req: CompletionRequest) -> Result<Self, Self::Error> {
+        let prompt = match req.prompt {
+            Prompt::Text(prompt) => prompt,
+            Prompt::Batch(mut prompts) if prompts.len() == 1 => prompts.remove(0),
+            Prompt::Batch(_) => {
+                return Err(crate::Error::InvalidRequest {
+                    message: "only a single prompt is supported".to_owned(),
+                    param: Some("prompt".to_owned()),
+                });
+            }
+        };
+        Ok(ChatRequest::builder()
+            .model(&req.model)
+            .messages(vec![
+                Message::builder()
+                    .role(Role::User)
+                    .content(Content::Text(prompt))
+                    .build(),
+            ])
+            .stream(req.stream)
+            .stream_options(req.stream_options)
+            .max_tokens(req.max_tokens)
+            .stop(req.stop)
+            .n(req.n)
+            .user(req.user)
+            .build())
+    }
+}
+
+/// How a multi-turn conversation is sent upstream
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageMode {
+    /// Flatten the history into a single `role:content;` user message
+    #[default]
+    Compress,
+    /// Send the history as upstream messages, compressing only if the upstream rejects it
+    Native,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default, TypedBuilder)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default, TypedBuilder)]
 pub struct Message {
     #[builder(default, setter(into))]
     pub role: Option<Role>,
     #[builder(default, setter(into))]
     pub content: Option<Content>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[builder(default, setter(into))]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// Call answered by a `tool` message
+    #[serde(default, skip_serializing)]
+    #[builder(default)]
+    pub tool_call_id: Option<String>,
+    /// Participant or function name, which the upstream has no field for
+    #[serde(default, skip_serializing)]
+    #[builder(default)]
+    pub name: Option<String>,
+    /// Refusal of an assistant message, kept as its content
+    #[serde(default, skip_serializing)]
+    #[builder(default)]
+    pub refusal: Option<String>,
+    /// Legacy single function call of an assistant message
+    #[serde(default, skip_serializing)]
+    #[builder(default)]
+    pub function_call: Option<FunctionCall>,
+}
+
+/// What to do with content parts the upstream can't take, such as images
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnsupportedContent {
+    /// Leave the part out
+    Drop,
+    /// Replace the part with a text note such as `[image omitted]`
+    #[default]
+    Placeholder,
+    /// Fail the request with an `invalid_request_error`
+    Reject,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Content {
     Text(String),
     Vec(Vec<ContentItem>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Content {
+    /// Plain text of the content, joining parts with newlines
+    pub fn to_text(&self) -> String {
+        match self {
+            Content::Text(text) => text.clone(),
+            Content::Vec(vec) => vec
+                .iter()
+                .filter_map(ContentItem::as_text)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+/// Content part of any type; media parts keep only their type
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ContentItem {
     #[serde(rename = "type")]
-    r#type: String,
-    pub text: String,
+    pub r#type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub refusal: Option<String>,
 }
 
-fn deserialize_model<'de, D>(deserializer: D) -> Result<String, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let model = String::deserialize(deserializer)?;
-    let model = match model.as_str() {
-        "claude-3-haiku" => "claude-3-haiku-20240307",
-        "llama-3.3-70b" => "meta-llama/Llama-3.3-70B-Instruct-Turbo",
-        "mixtral-small-3" => "mistralai/Mistral-Small-24B-Instruct-2501",
-        "gpt-4o-mini" => "gpt-4o-mini",
-        "o4-mini" => "o4-mini",
-        _ => model.as_str(),
-    };
-    Ok(model.to_owned())
+impl ContentItem {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self {
+            r#type: "text".to_owned(),
+            text: Some(text.into()),
+            refusal: None,
+        }
+    }
+
+    /// Text of a text or refusal part, `None` for media parts
+    pub fn as_text(&self) -> Option<&str> {
+        match self.r#type.as_str() {
+            "text" | "input_text" | "output_text" => self.text.as_deref(),
+            "refusal" => self.refusal.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Text standing in for a part the upstream can't take
+    fn placeholder(&self) -> String {
+        let kind = match self.r#type.as_str() {
+            "image_url" | "input_image" => "image",
+            "input_audio" => "audio",
+            "file" | "input_file" => "file",
+            other => other,
+        };
+        format!("[{kind} omitted]")
+    }
 }
 
 fn deserialize_message<'de, D>(deserializer: D) -> Result<Vec<Message>, D::Error>
@@ -66,11 +392,11 @@ where
 {
     let mut message: Vec<Message> = Vec::deserialize(deserializer)?;
     for message in &mut message {
-        if let Some(role) = message.role.as_mut() {
-            if matches!(role, Role::System) {
-                *role = Role::User;
-            }
+        if message.content.is_none() {
+            message.content = message.refusal.take().map(Content::Text);
         }
+        tools::render_message(message);
+        message.role = message.role.map(Role::upstream);
     }
     Ok(message)
 }
@@ -84,8 +410,8 @@ pub fn compress_messages(messages: &[Message]) -> String {
             match msg {
                 Content::Text(msg) => key.push_str(&format!("{role}:{msg};\n")),
                 Content::Vec(vec) => {
-                    for item in vec {
-                        key.push_str(&format!("{role}:{};\n", item.text));
+                    for text in vec.iter().filter_map(ContentItem::as_text) {
+                        key.push_str(&format!("{role}:{text};\n"));
                     }
                 }
             }
@@ -106,6 +432,35 @@ impl ChatRequest {
             self.compressed = true;
         }
     }
+
+    /// Map the history onto upstream messages: plain text content, with
+    /// consecutive messages of the same role merged into one turn.
+    pub fn native_messages(&mut self) {
+        let mut messages: Vec<Message> = Vec::with_capacity(self.messages.len());
+        for message in self.messages.drain(..) {
+            let (Some(role), Some(content)) = (message.role, message.content) else {
+                continue;
+            };
+            let text = content.to_text();
+            match messages.last_mut() {
+                Some(Message {
+                    role: Some(last_role),
+                    content: Some(Content::Text(last)),
+                    ..
+                }) if *last_role == role => {
+                    last.push_str("\n\n");
+                    last.push_str(&text);
+                }
+                _ => messages.push(
+                    Message::builder()
+                        .role(role)
+                        .content(Content::Text(text))
+                        .build(),
+                ),
+            }
+        }
+        self.messages = messages;
+    }
 }
 
 // ==================== Duck APi Response Body ====================
@@ -118,10 +473,39 @@ pub struct DuckChatCompletion {
     pub model: Option<String>,
 }
 
+/// Error event ending an upstream reply, e.g.
+/// `{"action":"error","status":429,"type":"ERR_CONVERSATION_LIMIT"}`
+#[derive(Deserialize)]
+pub struct DuckChatError {
+    pub action: String,
+    pub status: Option<u16>,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+}
+
 fn default_id() -> String {
     "chatcmpl-123".to_owned()
 }
 
+/// Unique object id with `prefix`, e.g. `msg_18a3f...`
+pub fn gen_id(prefix: &str) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{prefix}_{nanos:x}{count:04x}")
+}
+
+/// Current unix time in seconds
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
 // ==================== Response Body ====================
 #[derive(Serialize, TypedBuilder)]
 pub struct ChatCompletion<'a> {
@@ -154,6 +538,10 @@ pub struct Choice {
     #[serde(skip_serializing_if = "Option::is_none")]
     delta: Option<Message>,
 
+    #[builder(default, setter(into))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+
     #[builder(setter(into))]
     logprobs: Option<String>,
 
@@ -161,9 +549,195 @@ pub struct Choice {
     finish_reason: Option<&'static str>,
 }
 
-#[derive(Serialize, TypedBuilder)]
+#[derive(Serialize, Clone, Copy)]
 pub struct Usage {
-    prompt_tokens: i32,
-    completion_tokens: i32,
-    total_tokens: i32,
+    prompt_tokens: u32,
+    completion_tokens: u32,
+    total_tokens: u32,
+}
+
+impl Usage {
+    pub fn new(prompt_tokens: u32, completion_tokens: u32) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(messages: serde_json::Value) -> ChatRequest {
+        serde_json::from_value(json!({"model": "gpt-4o-mini", "messages": messages})).unwrap()
+    }
+
+    #[test]
+    fn compress_messages_flattens_history() {
+        let mut req = request(json!([
+            {"role": "system", "content": "Be brief"},
+            {"role": "user", "content": "Hi"},
+        ]));
+        req.compress_messages();
+        assert_eq!(req.messages.len(), 1);
+        assert!(req.compressed);
+        let content = serde_json::to_value(&req.messages[0]).unwrap();
+        assert_eq!(content["content"], "user:Be brief;\nuser:Hi;\n");
+    }
+
+    #[test]
+    fn native_messages_keeps_turns() {
+        let mut req = request(json!([
+            {"role": "system", "content": "Be brief"},
+            {"role": "user", "content": [{"type": "text", "text": "Hi"}]},
+            {"role": "assistant", "content": "Hello"},
+            {"role": "user", "content": "Bye"},
+        ]));
+        req.native_messages();
+        let messages = serde_json::to_value(&req.messages).unwrap();
+        assert_eq!(
+            messages,
+            json!([
+                {"role": "user", "content": "Be brief\n\nHi"},
+                {"role": "assistant", "content": "Hello"},
+                {"role": "user", "content": "Bye"},
+            ])
+        );
+    }
+
+    #[test]
+    fn message_mode_is_not_sent_upstream() {
+        let req: ChatRequest = serde_json::from_value(json!({
+            "model": "gpt-4o-mini",
+            "messages": [],
+            "message_mode": "native",
+        }))
+        .unwrap();
+        assert_eq!(req.message_mode, Some(MessageMode::Native));
+        let body = serde_json::to_value(&req).unwrap();
+        assert!(body.get("message_mode").is_none());
+    }
+
+    #[test]
+    fn registry_resolves_ids_and_aliases() {
+        let models: Vec<ModelConfig> = serde_yaml::from_str(
+            r#"
+- id: fast
+  upstream: gpt-4o-mini
+  owned_by: openai
+  created: 1
+  context_window: 128000
+  aliases: [quick]
+- id: off
+  upstream: o4-mini
+  owned_by: openai
+  created: 1
+  context_window: 200000
+  enabled: false
+"#,
+        )
+        .unwrap();
+        let registry = ModelRegistry::new(models);
+        for name in ["fast", "quick", "gpt-4o-mini"] {
+            assert_eq!(registry.resolve(name).unwrap().id, "fast");
+        }
+        assert!(registry.resolve("off").is_none());
+        let ids: Vec<_> = registry.enabled().map(|model| model.id.as_str()).collect();
+        assert_eq!(ids, ["fast"]);
+    }
+
+    #[test]
+    fn resolve_model_maps_to_upstream_ids() {
+        let registry = ModelRegistry::default();
+        let resolved = |model: &str, default| {
+            let mut req = ChatRequest::builder()
+                .model(model)
+                .messages(Vec::new())
+                .build();
+            req.resolve_model(&registry, default).map(|_| req.model)
+        };
+        assert_eq!(
+            resolved("claude-3-haiku", None).unwrap(),
+            "claude-3-haiku-20240307"
+        );
+        assert_eq!(
+            resolved("claude-3-haiku-20240307", None).unwrap(),
+            "claude-3-haiku-20240307"
+        );
+        assert_eq!(resolved("gpt-4o-mini", None).unwrap(), "gpt-4o-mini");
+        assert_eq!(resolved("unknown", Some("o4-mini")).unwrap(), "o4-mini");
+        assert!(matches!(
+            resolved("unknown", None),
+            Err(crate::Error::ModelNotFound(_))
+        ));
+    }
+
+    fn multimodal() -> serde_json::Value {
+        json!([
+            {"role": "developer", "content": "Be brief"},
+            {"role": "user", "name": "ada", "content": [
+                {"type": "text", "text": "What is this?"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}},
+                {"type": "input_audio", "input_audio": {"data": "AAAA", "format": "wav"}},
+            ]},
+            {"role": "assistant", "content": null, "refusal": "I can't help with that."},
+            {"role": "assistant", "content": null,
+             "function_call": {"name": "lookup", "arguments": "{}"}},
+            {"role": "function", "name": "lookup", "content": "nothing"},
+        ])
+    }
+
+    #[test]
+    fn full_message_schema_is_accepted() {
+        let req = request(multimodal());
+        let roles: Vec<_> = req.messages.iter().map(|m| m.role.unwrap()).collect();
+        assert_eq!(
+            roles,
+            [
+                Role::User,
+                Role::User,
+                Role::Assistant,
+                Role::Assistant,
+                Role::User
+            ]
+        );
+        let text = |i: usize| req.messages[i].content.as_ref().unwrap().to_text();
+        assert_eq!(text(1), "What is this?");
+        assert_eq!(text(2), "I can't help with that.");
+        assert_eq!(
+            text(3),
+            r#"{"tool_calls":[{"arguments":{},"name":"lookup"}]}"#
+        );
+        assert_eq!(text(4), "Tool result for call lookup:\nnothing");
+    }
+
+    #[test]
+    fn unsupported_content_policies() {
+        let mut req = request(multimodal());
+        req.resolve_unsupported(UnsupportedContent::Placeholder)
+            .unwrap();
+        assert_eq!(
+            req.messages[1].content.as_ref().unwrap().to_text(),
+            "What is this?\n[image omitted]\n[audio omitted]"
+        );
+
+        let mut req = request(multimodal());
+        req.resolve_unsupported(UnsupportedContent::Drop).unwrap();
+        assert_eq!(
+            serde_json::to_value(&req.messages[1].content).unwrap(),
+            json!([{"type": "text", "text": "What is this?"}])
+        );
+
+        let mut req = request(multimodal());
+        let err = req
+            .resolve_unsupported(UnsupportedContent::Reject)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            crate::Error::InvalidRequest { param: Some(param), .. } if param == "messages[1].content[1].type"
+        ));
+    }
 }
@@ -0,0 +1,641 @@
+//! Ollama API (`/api/chat`, `/api/generate`, `/api/tags`) on top of the chat pipeline
+
+use crate::auth;
+use crate::config::ApiKey;
+use crate::error::Error;
+use crate::model::{
+    ChatRequest, Content, ContentItem, DuckChatCompletion, Message, Role, Stop, now,
+};
+use crate::process::{
+    CompletionHook, OutputLimit, in_current_span, process_stream_until,
+    process_stream_with_chunk_until,
+};
+use crate::serve::AppState;
+use crate::session::conversation_id;
+use crate::tokenizer;
+use axum::{
+    Json,
+    body::Body,
+    extract::{State, rejection::JsonRejection},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use axum_extra::{
+    TypedHeader,
+    extract::WithRejection,
+    headers::{Authorization, authorization::Bearer},
+};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::convert::Infallible;
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex, PoisonError};
+
+// ==================== Request Body ====================
+#[derive(Debug, Deserialize)]
+pub struct OllamaChatRequest {
+    pub model: String,
+    pub messages: Vec<OllamaMessage>,
+    /// Ollama streams unless told otherwise
+    #[serde(default = "default_stream")]
+    pub stream: bool,
+    #[serde(default)]
+    pub options: Options,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OllamaMessage {
+    pub role: Role,
+    pub content: String,
+    /// Base64 images, left to the `unsupported_content` policy
+    #[serde(default)]
+    pub images: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GenerateRequest {
+    pub model: String,
+    pub prompt: String,
+    #[serde(default)]
+    pub system: Option<String>,
+    #[serde(default)]
+    pub images: Vec<String>,
+    #[serde(default = "default_stream")]
+    pub stream: bool,
+    #[serde(default)]
+    pub options: Options,
+}
+
+/// Model options; only the limits of the reply apply upstream
+#[derive(Debug, Default, Deserialize)]
+pub struct Options {
+    /// Most tokens to generate, negative for no limit
+    #[serde(default)]
+    pub num_predict: Option<i64>,
+    #[serde(default)]
+    pub stop: Vec<String>,
+}
+
+impl Options {
+    fn max_tokens(&self) -> Option<u32> {
+        u32::try_from(self.num_predict?).ok()
+    }
+}
+
+fn default_stream() -> bool {
+    true
+}
+
+/// `text` followed by a part for each of `images`
+fn content(text: String, images: &[String]) -> Content {
+    if images.is_empty() {
+        return Content::Text(text);
+    }
+    let images = images.iter().map(|_| ContentItem {
+        r#type: "image".to_owned(),
+        text: None,
+        refusal: None,
+    });
+    Content::Vec(
+        std::iter::once(ContentItem::text(text))
+            .chain(images)
+            .collect(),
+    )
+}
+
+/// Ollama model names may carry a `:latest` tag
+fn model_name(model: &str) -> &str {
+    model.strip_suffix(":latest").unwrap_or(model)
+}
+
+impl From<OllamaChatRequest> for ChatRequest {
+    fn from(req: OllamaChatRequest) -> Self {
+        let messages = req
+            .messages
+            .into_iter()
+            .map(|message| {
+                Message::builder()
+                    .role(message.role.upstream())
+                    .content(content(message.content, &message.images))
+                    .build()
+            })
+            .collect();
+        ChatRequest::builder()
+            .model(model_name(&req.model))
+            .messages(messages)
+            .max_tokens(req.options.max_tokens())
+            .stop(Some(Stop::Many(req.options.stop)))
+            .stream(req.stream)
+            .build()
+    }
+}
+
+impl From<GenerateRequest> for ChatRequest {
+    fn from(req: GenerateRequest) -> Self {
+        let system = req.system.map(|system| {
+            Message::builder()
+                .role(Role::User)
+                .content(Content::Text(system))
+                .build()
+        });
+        let prompt = Message::builder()
+            .role(Role::User)
+            .content(content(req.prompt, &req.images))
+            .build();
+        let messages = system.into_iter().chain(Some(prompt)).collect();
+        ChatRequest::builder()
+            .model(model_name(&req.model))
+            .messages(messages)
+            .max_tokens(req.options.max_tokens())
+            .stop(Some(Stop::Many(req.options.stop)))
+            .stream(req.stream)
+            .build()
+    }
+}
+
+/// Shape of the reply objects for each endpoint
+#[derive(Clone, Copy)]
+enum Api {
+    Chat,
+    Generate,
+}
+
+impl Api {
+    /// Reply object carrying `text`
+    fn object(self, model: &str, created: u64, text: &str, done: bool) -> Value {
+        let mut object = match self {
+            Api::Chat => json!({
+                "model": model,
+                "created_at": rfc3339(created),
+                "message": {"role": "assistant", "content": text},
+                "done": done,
+            }),
+            Api::Generate => json!({
+                "model": model,
+                "created_at": rfc3339(created),
+                "response": text,
+                "done": done,
+            }),
+        };
+        if done {
+            object["done_reason"] = json!("stop");
+        }
+        object
+    }
+
+    /// Final reply object, with the token counts Ollama reports on completion
+    fn done(
+        self,
+        model: &str,
+        created: u64,
+        text: &str,
+        prompt_tokens: u32,
+        reply: &OutputLimit,
+    ) -> Value {
+        let mut object = self.object(model, created, text, true);
+        object["done_reason"] = json!(reply.finish_reason().unwrap_or("stop"));
+        object["prompt_eval_count"] = json!(prompt_tokens);
+        object["eval_count"] = json!(tokenizer::count_text(model, reply.text()));
+        object
+    }
+}
+
+pub async fn chat(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    headers: HeaderMap,
+    WithRejection(Json(req), _): WithRejection<Json<OllamaChatRequest>, OllamaError>,
+) -> Result<Response, OllamaError> {
+    let key = state.valid_key(bearer)?;
+    let model = req.model.clone();
+    let mut body = ChatRequest::from(req);
+    state.resolve_request(key, &mut body)?;
+    Ok(handle(&state, key, &headers, body, model, Api::Chat).await?)
+}
+
+pub async fn generate(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    headers: HeaderMap,
+    WithRejection(Json(req), _): WithRejection<Json<GenerateRequest>, OllamaError>,
+) -> Result<Response, OllamaError> {
+    let key = state.valid_key(bearer)?;
+    let model = req.model.clone();
+    let mut body = ChatRequest::from(req);
+    state.resolve_request(key, &mut body)?;
+    Ok(handle(&state, key, &headers, body, model, Api::Generate).await?)
+}
+
+pub async fn tags(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> crate::Result<Response> {
+    let key = state.valid_key(bearer)?;
+
+    let models: Vec<_> = state
+        .models
+        .enabled()
+        .filter(|card| auth::allows(key, card))
+        .map(|card| {
+            json!({
+                "name": card.id,
+                "model": card.id,
+                "modified_at": rfc3339(card.created),
+                "size": 0,
+                "digest": "",
+                "details": {
+                    "format": "",
+                    "family": card.owned_by,
+                    "families": [card.owned_by],
+                    "parameter_size": "",
+                    "quantization_level": "",
+                },
+            })
+        })
+        .collect();
+
+    Ok(Json(json!({ "models": models })).into_response())
+}
+
+async fn handle(
+    state: &AppState,
+    key: Option<&ApiKey>,
+    headers: &HeaderMap,
+    mut body: ChatRequest,
+    model: String,
+    api: Api,
+) -> crate::Result<Response> {
+    let conversation = conversation_id(headers, None, key);
+    let (resp, on_complete) = crate::route::forward(state, &mut body, conversation).await?;
+    let prompt_tokens = tokenizer::count_messages(&body.model, &body.messages);
+    let output = OutputLimit::new(&body.model, body.output_limit(), body.stop_sequences());
+    if body.stream.unwrap_or_default() {
+        Ok(stream_response(
+            resp,
+            model,
+            api,
+            prompt_tokens,
+            output,
+            on_complete,
+        ))
+    } else {
+        single_response(resp, model, api, prompt_tokens, output, on_complete).await
+    }
+}
+
+/// Status and Ollama `{"error": message}` body of `err`
+fn error_parts(err: Error) -> (StatusCode, Value) {
+    let (status, error, _) = err.parts();
+    let error = serde_json::to_value(error).unwrap_or_default();
+    (status, json!({"error": error["message"]}))
+}
+
+/// Error of the Ollama API, answered as `{"error": message}`
+pub struct OllamaError(Error);
+
+impl From<Error> for OllamaError {
+    fn from(err: Error) -> Self {
+        Self(err)
+    }
+}
+
+impl From<JsonRejection> for OllamaError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self(rejection.into())
+    }
+}
+
+impl IntoResponse for OllamaError {
+    fn into_response(self) -> Response {
+        let (status, body) = error_parts(self.0);
+        (status, Json(body)).into_response()
+    }
+}
+
+fn ndjson_line(object: Value) -> Result<String, Infallible> {
+    Ok(format!("{object}\n"))
+}
+
+fn stream_response(
+    resp: reqwest::Response,
+    model: String,
+    api: Api,
+    prompt_tokens: u32,
+    output: OutputLimit,
+    on_complete: Option<CompletionHook>,
+) -> Response {
+    let reply = Arc::new(Mutex::new(output));
+    let reply_end = reply.clone();
+    let end_model = model.clone();
+
+    let lines = process_stream_with_chunk_until(
+        resp,
+        move |body: DuckChatCompletion| {
+            let Some(message) = body.message else {
+                return ControlFlow::Continue(Vec::new());
+            };
+            let (text, cut) = reply
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(&message);
+            let lines: Vec<_> = (!text.is_empty())
+                .then(|| ndjson_line(api.object(&model, body.created, &text, false)))
+                .into_iter()
+                .collect();
+            match cut {
+                Some(_) => ControlFlow::Break(lines),
+                None => ControlFlow::Continue(lines),
+            }
+        },
+        move |_| {
+            let mut reply = reply_end.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut lines = Vec::new();
+            if reply.finish_reason().is_none() {
+                let (text, _) = reply.flush();
+                if !text.is_empty() {
+                    lines.push(ndjson_line(api.object(&end_model, now(), &text, false)));
+                }
+            }
+            // a cut reply differs from what the upstream session holds
+            if let (None, Some(hook)) = (reply.finish_reason(), on_complete) {
+                hook(reply.text().to_owned());
+            }
+            lines.push(ndjson_line(api.done(
+                &end_model,
+                now(),
+                "",
+                prompt_tokens,
+                &reply,
+            )));
+            lines
+        },
+        |err| vec![ndjson_line(error_parts(err).1)],
+    )
+    .flat_map(futures_util::stream::iter);
+
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(in_current_span(lines)),
+    )
+        .into_response()
+}
+
+async fn single_response(
+    resp: reqwest::Response,
+    model: String,
+    api: Api,
+    prompt_tokens: u32,
+    mut output: OutputLimit,
+    on_complete: Option<CompletionHook>,
+) -> crate::Result<Response> {
+    let mut created = None;
+    process_stream_until(resp, |body| {
+        created.get_or_insert(body.created);
+        if let Some(message) = body.message
+            && let (_, Some(_)) = output.push(&message)
+        {
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    })
+    .await?;
+    if output.finish_reason().is_none() {
+        output.flush();
+    }
+
+    // a cut reply differs from what the upstream session holds
+    if let (None, Some(hook)) = (output.finish_reason(), on_complete) {
+        hook(output.text().to_owned());
+    }
+
+    let created = created.unwrap_or_else(now);
+    let reply = api.done(&model, created, output.text(), prompt_tokens, &output);
+    Ok(Json(reply).into_response())
+}
+
+/// Format a unix timestamp as an RFC 3339 UTC date-time
+fn rfc3339(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    let (hour, minute, second) = (rem / 3600, rem % 3600 / 60, rem % 60);
+
+    // civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Scenario;
+    use crate::mock::test_util::{post_json, spawn_proxy, spawn_proxy_with};
+
+    #[test]
+    fn rfc3339_formats_unix_time() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(1_700_000_000), "2023-11-14T22:13:20Z");
+        assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn model_name_strips_latest_tag() {
+        let req: OllamaChatRequest = serde_json::from_value(json!({
+            "model": "llama-3.3-70b:latest",
+            "messages": [{"role": "system", "content": "Be brief"}],
+        }))
+        .unwrap();
+        let body = ChatRequest::from(req);
+        assert_eq!(body.model, "llama-3.3-70b");
+        assert_eq!(body.stream, Some(true));
+        assert_eq!(body.messages[0].role, Some(Role::User));
+    }
+
+    fn lines(body: &str) -> Vec<Value> {
+        body.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn chat_streams_ndjson() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_json(
+            addr,
+            "/api/chat",
+            json!({"model": "gpt-4o-mini", "messages": [{"role": "user", "content": "Hi"}]}),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/x-ndjson");
+        let lines = lines(&resp.text().await.unwrap());
+        let text: String = lines
+            .iter()
+            .map(|line| line["message"]["content"].as_str().unwrap())
+            .collect();
+        assert_eq!(text, "Hello, world!");
+        let last = lines.last().unwrap();
+        assert_eq!(last["done"], true);
+        assert_eq!(last["done_reason"], "stop");
+        assert!(lines[..lines.len() - 1].iter().all(|l| l["done"] == false));
+    }
+
+    #[tokio::test]
+    async fn generate_single_response() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_json(
+            addr,
+            "/api/generate",
+            json!({"model": "gpt-4o-mini", "prompt": "Hi", "stream": false}),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["response"], "Hello, world!");
+        assert_eq!(body["done"], true);
+        assert_eq!(body["created_at"], "2023-11-14T22:13:20Z");
+    }
+
+    #[tokio::test]
+    async fn disconnect_is_an_error() {
+        let (addr, _) = spawn_proxy(Scenario::Disconnect.into()).await;
+        let resp = post_json(
+            addr,
+            "/api/generate",
+            json!({"model": "gpt-4o-mini", "prompt": "Hi", "stream": false}),
+        )
+        .await;
+        assert_eq!(resp.status(), 502);
+
+        let resp = post_json(
+            addr,
+            "/api/generate",
+            json!({"model": "gpt-4o-mini", "prompt": "Hi"}),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let lines = lines(&resp.text().await.unwrap());
+        let last = lines.last().unwrap();
+        assert!(last["error"].as_str().unwrap().contains("broke off"));
+        assert!(lines.iter().all(|l| l["done"] != true));
+    }
+
+    #[tokio::test]
+    async fn unsupported_content_policy_applies() {
+        let (addr, mock) = spawn_proxy_with(Scenario::Normal.into(), |config| {
+            config.unsupported_content = crate::model::UnsupportedContent::Placeholder
+        })
+        .await;
+        let resp = post_json(
+            addr,
+            "/api/generate",
+            json!({"model": "gpt-4o-mini", "prompt": "Describe", "images": ["AAAA"], "stream": false}),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(
+            mock.requests()[0].body["messages"][0]["content"],
+            json!([
+                {"type": "text", "text": "Describe"},
+                {"type": "text", "text": "[image omitted]"},
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn options_limit_the_reply() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let generate = |stream, options: Value| {
+            post_json(
+                addr,
+                "/api/generate",
+                json!({"model": "gpt-4o-mini", "prompt": "Hi", "stream": stream, "options": options}),
+            )
+        };
+
+        let body: Value = generate(false, json!({"num_predict": 1}))
+            .await
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(body["response"], "Hello");
+        assert_eq!(body["done_reason"], "length");
+
+        let body: Value = generate(false, json!({"num_predict": -1, "stop": ["world"]}))
+            .await
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(body["response"], "Hello, ");
+        assert_eq!(body["done_reason"], "stop");
+
+        let lines = lines(
+            &generate(true, json!({"stop": ["world"]}))
+                .await
+                .text()
+                .await
+                .unwrap(),
+        );
+        let text: String = lines
+            .iter()
+            .map(|line| line["response"].as_str().unwrap())
+            .collect();
+        assert_eq!(text, "Hello, ");
+        assert_eq!(lines.last().unwrap()["done_reason"], "stop");
+    }
+
+    #[tokio::test]
+    async fn errors_are_ollama_shaped() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = reqwest::Client::new()
+            .post(format!("http://{addr}/api/chat"))
+            .json(&json!({"model": "gpt-4o-mini", "messages": []}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 401);
+        let body: Value = resp.json().await.unwrap();
+        assert!(body["error"].is_string());
+        assert_eq!(body.as_object().unwrap().len(), 1);
+
+        let resp = post_json(
+            addr,
+            "/api/chat",
+            json!({"model": "unknown", "messages": [{"role": "user", "content": "Hi"}]}),
+        )
+        .await;
+        assert_eq!(resp.status(), 404);
+        let body: Value = resp.json().await.unwrap();
+        assert!(body["error"].as_str().unwrap().contains("unknown"));
+    }
+
+    #[tokio::test]
+    async fn tags_lists_models() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = reqwest::Client::new()
+            .get(format!("http://{addr}/api/tags"))
+            .bearer_auth("sk-test")
+            .send()
+            .await
+            .unwrap();
+        let body: Value = resp.json().await.unwrap();
+        let names: Vec<&str> = body["models"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["name"].as_str().unwrap())
+            .collect();
+        let registry = crate::model::ModelRegistry::default();
+        let ids: Vec<&str> = registry.enabled().map(|card| card.id.as_str()).collect();
+        assert_eq!(names, ids);
+    }
+}
@@ -1,190 +1,1343 @@
-use crate::model::{ChatCompletion, Choice, Content, DuckChatCompletion, Message, Role, Usage};
-use axum::response::{IntoResponse, Response, Sse, sse::Event};
+use crate::model::{
+    ChatCompletion, Choice, Content, DuckChatCompletion, DuckChatError, Message, Role, Usage,
+    gen_id, now,
+};
+use crate::tokenizer::{self, TokenCounter};
+use crate::tools::{self, Reply, Tool, ToolCall, ToolCallDetector};
+use axum::http::header::CONTENT_TYPE;
+use axum::response::sse::{Event, KeepAlive};
+use axum::response::{IntoResponse, Response, Sse};
 use axum::{Error, Json};
-use eventsource_stream::Eventsource;
+use eventsource_stream::{EventStreamError, Eventsource};
 use futures_util::{Stream, StreamExt};
+use std::ops::ControlFlow;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tracing::Span;
 
-type EventResult = Result<Event, axum::Error>;
+pub type EventResult = Result<Event, axum::Error>;
+
+/// Called with the full reply once the upstream stream completes
+pub type CompletionHook = Box<dyn FnOnce(String) + Send>;
+
+/// OpenAI object family produced by [`ChatProcess`]
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompletionKind {
+    /// `chat.completion` objects with messages
+    #[default]
+    Chat,
+    /// Legacy `text_completion` objects with text
+    Text,
+}
+
+impl CompletionKind {
+    fn object(self, stream: bool) -> &'static str {
+        match (self, stream) {
+            (CompletionKind::Chat, false) => "chat.completion",
+            (CompletionKind::Chat, true) => "chat.completion.chunk",
+            (CompletionKind::Text, _) => "text_completion",
+        }
+    }
+
+    fn id_prefix(self) -> &'static str {
+        match self {
+            CompletionKind::Chat => "chatcmpl",
+            CompletionKind::Text => "cmpl",
+        }
+    }
+}
 
 #[derive(typed_builder::TypedBuilder)]
 pub struct ChatProcess {
     stream: Option<bool>,
     model: String,
     resp: reqwest::Response,
+    /// Upstream replies of the further choices when `n > 1`
+    #[builder(default)]
+    other_choices: Vec<reqwest::Response>,
+    /// Records the first choice into the upstream session
+    #[builder(default)]
+    on_complete: Option<CompletionHook>,
+    #[builder(default)]
+    kind: CompletionKind,
+    /// Estimated prompt tokens, reported in usage
+    #[builder(default)]
+    prompt_tokens: u32,
+    /// Send a final usage chunk when streaming
+    #[builder(default)]
+    include_usage: bool,
+    /// Reply token budget
+    #[builder(default)]
+    max_tokens: Option<u32>,
+    /// Sequences ending the reply, excluded from the output
+    #[builder(default)]
+    stop: Vec<String>,
+    /// Tools whose emulated calls are parsed from the reply
+    #[builder(default)]
+    tools: Vec<Tool>,
+    /// Opening chunk already sent for the streamed reply
+    #[builder(default)]
+    opened: Option<Opened>,
+    /// Interval of SSE heartbeats
+    #[builder(default)]
+    keep_alive: Option<Duration>,
+}
+
+/// Id and creation time of the opening chunk of a streamed reply
+#[derive(Clone)]
+pub struct Opened {
+    id: String,
+    created: u64,
+}
+
+/// Stream a reply of `kind` while its upstream request is still being
+/// made: an empty chunk of each of the `choices`, carrying the role of a
+/// chat reply, goes out at once, and the reply follows from the process
+/// `start` makes.
+pub fn stream_opened<F, Fut>(
+    kind: CompletionKind,
+    model: String,
+    choices: usize,
+    keep_alive: Option<Duration>,
+    start: F,
+) -> Response
+where
+    F: FnOnce(Opened) -> Fut + Send + 'static,
+    Fut: Future<Output = crate::Result<ChatProcess>> + Send + 'static,
+{
+    let opened = Opened {
+        id: gen_id(kind.id_prefix()),
+        created: now(),
+    };
+    let events = async_stream::stream! {
+        for index in 0..choices {
+            let mut writer = ChunkWriter {
+                kind,
+                model: model.clone(),
+                index,
+                first_message: true,
+            };
+            yield writer.chunk(opened.id.clone(), opened.created, Some(String::new()), None);
+        }
+        let process = match start(opened).await {
+            Ok(process) => process.checked().await,
+            Err(err) => Err(err),
+        };
+        match process {
+            Ok(process) => {
+                let mut events = std::pin::pin!(process.into_events());
+                while let Some(event) = events.next().await {
+                    yield event;
+                }
+            }
+            Err(err) => yield error_event(err),
+        }
+    };
+    sse_response(events, keep_alive)
+}
+
+/// OpenAI error event ending a stream
+fn error_event(err: crate::Error) -> EventResult {
+    tracing::info!("streamed reply failed: {err}");
+    let (_, error, _) = err.parts();
+    Event::default()
+        .json_data(serde_json::json!({ "error": error }))
+        .map_err(Error::new)
+}
+
+/// SSE response of `events`, with comment heartbeats every `keep_alive`
+pub fn sse_response<S>(events: S, keep_alive: Option<Duration>) -> Response
+where
+    S: Stream<Item = EventResult> + Send + 'static,
+{
+    let sse = Sse::new(in_current_span(events));
+    match keep_alive {
+        Some(interval) => sse
+            .keep_alive(KeepAlive::new().interval(interval))
+            .into_response(),
+        None => sse.into_response(),
+    }
+}
+
+/// Response body stream polled and dropped in the span of its request,
+/// which the handler leaves once it returns the response
+pub struct InSpan<S> {
+    stream: Option<Pin<Box<S>>>,
+    span: Span,
+}
+
+/// `stream` kept in the current span
+pub fn in_current_span<S>(stream: S) -> InSpan<S> {
+    InSpan {
+        stream: Some(Box::pin(stream)),
+        span: Span::current(),
+    }
+}
+
+impl<S: Stream> Stream for InSpan<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = &mut *self;
+        let _entered = this.span.enter();
+        match &mut this.stream {
+            Some(stream) => stream.as_mut().poll_next(cx),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+impl<S> Drop for InSpan<S> {
+    fn drop(&mut self) {
+        // cancelling the upstream read logs what was read so far
+        let _entered = self.span.enter();
+        self.stream.take();
+    }
+}
+
+/// Applies the request's output limits to the upstream reply
+pub struct OutputLimit {
+    counter: TokenCounter,
+    max_tokens: Option<u32>,
+    stop: Vec<String>,
+    /// Text held back while it may be the start of a stop sequence
+    pending: String,
+    /// Stop sequence that ended the reply
+    stopped_by: Option<String>,
+    /// Whether the token budget ended the reply
+    truncated: bool,
+}
+
+impl OutputLimit {
+    pub fn new(model: &str, max_tokens: Option<u32>, stop: Vec<String>) -> Self {
+        Self {
+            counter: TokenCounter::new(model),
+            max_tokens,
+            stop,
+            pending: String::new(),
+            stopped_by: None,
+            truncated: false,
+        }
+    }
+
+    /// Stop sequence that ended the reply, if one did
+    pub fn stop_sequence(&self) -> Option<&str> {
+        self.stopped_by.as_deref()
+    }
+
+    /// Finish reason of a reply a limit ended
+    pub fn finish_reason(&self) -> Option<&'static str> {
+        if self.truncated {
+            Some("length")
+        } else {
+            self.stopped_by.as_ref().map(|_| "stop")
+        }
+    }
+
+    /// Reply forwarded so far
+    pub fn text(&self) -> &str {
+        self.counter.text()
+    }
+
+    /// Accept `delta` from the upstream, returning the part to forward and,
+    /// once a limit is reached, the finish reason of the reply
+    pub fn push(&mut self, delta: &str) -> (String, Option<&'static str>) {
+        self.pending.push_str(delta);
+        let found = self
+            .stop
+            .iter()
+            .filter_map(|stop| Some((self.pending.find(stop.as_str())?, stop)))
+            .min_by_key(|(at, _)| *at);
+        if let Some((at, stop)) = found {
+            let stop = stop.clone();
+            self.pending.truncate(at);
+            let (text, finish_reason) = self.release(at);
+            if finish_reason.is_none() {
+                self.stopped_by = Some(stop);
+            }
+            return (text, finish_reason.or(Some("stop")));
+        }
+
+        let held = self
+            .stop
+            .iter()
+            .map(|stop| partial_match(&self.pending, stop))
+            .max()
+            .unwrap_or_default();
+        self.release(self.pending.len() - held)
+    }
+
+    /// Release the text held back once the upstream reply is complete
+    pub fn flush(&mut self) -> (String, Option<&'static str>) {
+        self.release(self.pending.len())
+    }
+
+    /// Forward the first `len` bytes of the pending text within the token budget
+    fn release(&mut self, len: usize) -> (String, Option<&'static str>) {
+        let text: String = self.pending.drain(..len).collect();
+        let start = self.counter.text().len();
+        let tokens = self.counter.push(&text);
+        match self.max_tokens {
+            Some(max) if tokens > max => {
+                self.counter.truncate(max);
+                self.pending.clear();
+                self.truncated = true;
+                let text = self.counter.text().get(start..).unwrap_or_default();
+                (text.to_owned(), Some("length"))
+            }
+            _ => (text, None),
+        }
+    }
+}
+
+/// Length of the longest suffix of `text` that is a proper prefix of `stop`
+fn partial_match(text: &str, stop: &str) -> usize {
+    (1..stop.len().min(text.len() + 1))
+        .rev()
+        .find(|&len| {
+            let start = text.len() - len;
+            text.is_char_boundary(start) && stop.as_bytes().starts_with(&text.as_bytes()[start..])
+        })
+        .unwrap_or_default()
+}
+
+/// Builds the chunks of a streamed reply
+struct ChunkWriter {
+    kind: CompletionKind,
+    model: String,
+    /// Index of the choice the chunks belong to
+    index: usize,
+    /// Only the first content chunk carries the role
+    first_message: bool,
+}
+
+impl ChunkWriter {
+    fn chunk(
+        &mut self,
+        id: String,
+        created: u64,
+        content: Option<String>,
+        finish_reason: Option<&'static str>,
+    ) -> EventResult {
+        if self.kind == CompletionKind::Text {
+            let text = content.unwrap_or_default();
+            return text_chunk(&self.model, id, created, self.index, text, finish_reason);
+        }
+        let delta = match content {
+            Some(content) => self.delta(Some(Content::Text(content)), None),
+            None => Message::default(),
+        };
+        self.chat_chunk(id, created, delta, finish_reason)
+    }
+
+    fn tool_calls(&mut self, id: String, created: u64, calls: Vec<ToolCall>) -> EventResult {
+        let calls = calls
+            .into_iter()
+            .enumerate()
+            .map(|(index, call)| ToolCall {
+                index: Some(index),
+                ..call
+            })
+            .collect();
+        let delta = self.delta(None, Some(calls));
+        self.chat_chunk(id, created, delta, None)
+    }
+
+    /// Delta of a chunk with output, the first one carrying the role
+    fn delta(&mut self, content: Option<Content>, tool_calls: Option<Vec<ToolCall>>) -> Message {
+        let role = std::mem::take(&mut self.first_message).then_some(Role::Assistant);
+        Message::builder()
+            .role(role)
+            .content(content)
+            .tool_calls(tool_calls)
+            .build()
+    }
+
+    fn chat_chunk(
+        &self,
+        id: String,
+        created: u64,
+        delta: Message,
+        finish_reason: Option<&'static str>,
+    ) -> EventResult {
+        let choice = Choice::builder()
+            .index(self.index)
+            .delta(delta)
+            .logprobs(None)
+            .finish_reason(finish_reason)
+            .build();
+
+        let chat_completion = ChatCompletion::builder()
+            .id(id)
+            .model(&self.model)
+            .object(self.kind.object(true))
+            .created(created)
+            .choices(vec![choice])
+            .build();
+
+        Event::default()
+            .json_data(chat_completion)
+            .map_err(Error::new)
+    }
+}
+
+/// Reply collected while streaming
+struct Collected {
+    id: String,
+    created: u64,
+    detector: ToolCallDetector,
+    output: OutputLimit,
+    writer: ChunkWriter,
+    /// The reply was cut short by a limit
+    truncated: bool,
+    /// Text of the tool calls the reply turned out to be
+    tool_calls: Option<String>,
+    /// The upstream ended the reply
+    done: bool,
+}
+
+impl Collected {
+    /// Chunk forwarding `text`
+    fn content(&mut self, text: String) -> EventResult {
+        self.writer
+            .chunk(self.id.clone(), self.created, Some(text), None)
+    }
+
+    /// Chunk finishing the reply
+    fn finish(&mut self, finish_reason: &'static str) -> EventResult {
+        self.writer
+            .chunk(self.id.clone(), self.created, None, Some(finish_reason))
+    }
+
+    /// Chunks of the output held back until the end of the reply, with the
+    /// finish reason when it is not a plain stop
+    fn end(&mut self) -> (Vec<EventResult>, Option<&'static str>) {
+        let text = match self.detector.finish() {
+            Reply::ToolCalls(calls) => {
+                self.tool_calls = Some(tools::render_calls(&calls));
+                let chunk = self.writer.tool_calls(self.id.clone(), self.created, calls);
+                return (vec![chunk], Some("tool_calls"));
+            }
+            Reply::Text(text) => text,
+        };
+        let (mut text, mut finish_reason) = self.output.push(&text);
+        if finish_reason.is_none() {
+            let rest;
+            (rest, finish_reason) = self.output.flush();
+            text.push_str(&rest);
+        }
+        self.truncated |= finish_reason.is_some();
+        let events = if text.is_empty() {
+            Vec::new()
+        } else {
+            vec![self.content(text)]
+        };
+        (events, finish_reason)
+    }
+
+    /// Full reply as the upstream session holds it
+    fn reply(&self) -> &str {
+        self.tool_calls
+            .as_deref()
+            .unwrap_or_else(|| self.output.text())
+    }
+}
+
+/// Upstream reply read to the end into one choice
+struct SingleChoice {
+    id: Option<String>,
+    created: Option<u64>,
+    content: String,
+    tool_calls: Option<Vec<ToolCall>>,
+    finish_reason: &'static str,
+    /// The reply is complete as the upstream session holds it
+    complete: bool,
 }
 
 impl ChatProcess {
     pub async fn into_response(self) -> crate::Result<Response> {
-        if self.resp.error_for_status_ref().err().is_some() {
-            let err = self.resp.text().await?;
-            Err(crate::Error::BadRequest(err))
+        let process = self.checked().await?;
+        if process.stream.unwrap_or_default() {
+            let keep_alive = process.keep_alive;
+            Ok(sse_response(process.into_events(), keep_alive))
         } else {
-            if self.stream.unwrap_or_default() {
-                self.into_stream_response().await
-            } else {
-                self.into_single_response().await
-            }
+            process.into_single_response().await
         }
     }
 
-    async fn into_stream_response(self) -> crate::Result<Response> {
-        let raw_model = self.model.clone();
-        let mut first_message = true;
-        let sse_stream = process_stream_with_chunk(
-            self.resp,
-            move |body: DuckChatCompletion| {
-                let choice = if let Some(content) = body.message {
-                    // only first message has role
-                    let role = if first_message {
-                        first_message = false;
-                        Some(Role::Assistant)
-                    } else {
-                        None
+    /// Fail with the upstream error of the first failed choice
+    async fn checked(mut self) -> crate::Result<Self> {
+        let failed = std::iter::once(&self.resp)
+            .chain(&self.other_choices)
+            .position(|resp| resp.error_for_status_ref().is_err());
+        let Some(index) = failed else {
+            return Ok(self);
+        };
+        let resp = match index {
+            0 => self.resp,
+            index => self.other_choices.swap_remove(index - 1),
+        };
+        let (status, headers) = (resp.status(), resp.headers().clone());
+        Err(crate::Error::upstream(status, &headers, resp.text().await?))
+    }
+
+    fn into_events(self) -> impl Stream<Item = EventResult> + use<> {
+        let resps = std::iter::once(self.resp).chain(self.other_choices);
+        // chunks of every choice share the id of the opening chunk or of
+        // the first upstream frame
+        let first_frame = Arc::new(OnceLock::new());
+        if let Some(opened) = self.opened {
+            let _ = first_frame.set((opened.id, opened.created));
+        }
+        let mut replies = Vec::new();
+        let mut choices = Vec::new();
+        let mut on_complete = self.on_complete;
+        let role_sent = first_frame.get().is_some();
+        for (index, resp) in resps.enumerate() {
+            let reply = Arc::new(Mutex::new(Collected {
+                id: String::new(),
+                created: 0,
+                detector: ToolCallDetector::new(self.tools.clone()),
+                output: OutputLimit::new(&self.model, self.max_tokens, self.stop.clone()),
+                writer: ChunkWriter {
+                    kind: self.kind,
+                    model: self.model.clone(),
+                    index,
+                    first_message: !role_sent,
+                },
+                truncated: false,
+                tool_calls: None,
+                done: false,
+            }));
+            replies.push(reply.clone());
+            let reply_end = reply.clone();
+            let reply_error = reply.clone();
+            let first_frame = first_frame.clone();
+            // the upstream session continues with the first choice
+            let on_complete = on_complete.take();
+            let choice = process_stream_with_chunk_until(
+                resp,
+                move |body: DuckChatCompletion| {
+                    let mut reply = reply.lock().unwrap_or_else(PoisonError::into_inner);
+                    if reply.id.is_empty() {
+                        (reply.id, reply.created) =
+                            first_frame.get_or_init(|| (body.id, body.created)).clone();
+                    }
+                    let Some(message) = body.message else {
+                        // upstream end of reply, after any held back output
+                        let (mut events, finish_reason) = reply.end();
+                        events.push(reply.finish(finish_reason.unwrap_or("stop")));
+                        return ControlFlow::Continue(events);
                     };
+                    let text = reply.detector.push(&message);
+                    let (text, finish_reason) = reply.output.push(&text);
+                    let mut events = Vec::new();
+                    if !text.is_empty() {
+                        events.push(reply.content(text));
+                    }
+                    match finish_reason {
+                        Some(reason) => {
+                            reply.truncated = true;
+                            events.push(reply.finish(reason));
+                            ControlFlow::Break(events)
+                        }
+                        None => ControlFlow::Continue(events),
+                    }
+                },
+                move |_| {
+                    let mut reply = reply_end.lock().unwrap_or_else(PoisonError::into_inner);
+                    let (mut events, finish_reason) = reply.end();
+                    if let Some(reason) = finish_reason {
+                        events.push(reply.finish(reason));
+                    }
+                    // a cut reply differs from what the upstream session holds
+                    if let (false, Some(hook)) = (reply.truncated, on_complete) {
+                        hook(reply.reply().to_owned());
+                    }
+                    reply.done = true;
+                    events
+                },
+                move |err| {
+                    // the output so far, finished as cut off by the error
+                    let mut reply = reply_error.lock().unwrap_or_else(PoisonError::into_inner);
+                    let (mut events, _) = reply.end();
+                    events.push(reply.finish("error"));
+                    events.push(error_event(err));
+                    events
+                },
+            );
+            choices.push(Box::pin(choice));
+        }
+
+        let model = self.model;
+        let kind = self.kind;
+        let prompt_tokens = self.prompt_tokens;
+        let include_usage = self.include_usage;
+        let end = futures_util::stream::once(async move {
+            let replies: Vec<_> = replies
+                .iter()
+                .map(|reply| reply.lock().unwrap_or_else(PoisonError::into_inner))
+                .collect();
+            // a choice cut off by the upstream leaves the response unterminated
+            if !replies.iter().all(|reply| reply.done) {
+                return Vec::new();
+            }
+            let mut events = Vec::new();
+            if include_usage {
+                let completion_tokens = replies
+                    .iter()
+                    .map(|reply| tokenizer::count_text(&model, reply.reply()))
+                    .sum();
+                let (id, created) = first_frame.get().cloned().unwrap_or_default();
+                // OpenAI's final usage chunk carries no choices
+                let usage_chunk = ChatCompletion::builder()
+                    .id(Some(id))
+                    .model(&model)
+                    .object(kind.object(true))
+                    .created(Some(created))
+                    .choices(Vec::new())
+                    .usage(Usage::new(prompt_tokens, completion_tokens))
+                    .build();
+                events.push(Event::default().json_data(usage_chunk).map_err(Error::new));
+            }
+            events.push(Ok(Event::default().data("[DONE]")));
+            events
+        });
+        futures_util::stream::select_all(choices)
+            .chain(end)
+            .flat_map(futures_util::stream::iter)
+    }
+
+    async fn into_single_response(self) -> crate::Result<Response> {
+        let resps = std::iter::once(self.resp).chain(self.other_choices);
+        let choices = futures_util::future::try_join_all(resps.map(|resp| {
+            single_choice(
+                resp,
+                &self.model,
+                self.max_tokens,
+                self.stop.clone(),
+                self.tools.clone(),
+            )
+        }))
+        .await?;
+
+        // the upstream session continues with the first choice
+        if let (Some(first), Some(hook)) = (choices.first(), self.on_complete)
+            && first.complete
+        {
+            hook(first.content.clone());
+        }
+
+        let completion_tokens = choices
+            .iter()
+            .map(|choice| tokenizer::count_text(&self.model, &choice.content))
+            .sum();
+        let usage = Usage::new(self.prompt_tokens, completion_tokens);
+        let id = choices.first().and_then(|choice| choice.id.clone());
+        let created = choices.first().and_then(|choice| choice.created);
+        let choices = choices
+            .into_iter()
+            .enumerate()
+            .map(|(index, choice)| match self.kind {
+                CompletionKind::Chat => {
+                    let content = choice
+                        .tool_calls
+                        .is_none()
+                        .then_some(Content::Text(choice.content));
                     Choice::builder()
-                        .index(0)
-                        .delta(
+                        .index(index)
+                        .message(
                             Message::builder()
-                                .role(role)
-                                .content(Content::Text(content))
+                                .role(Role::Assistant)
+                                .content(content)
+                                .tool_calls(choice.tool_calls)
                                 .build(),
                         )
                         .logprobs(None)
-                        .finish_reason(None)
+                        .finish_reason(choice.finish_reason)
                         .build()
-                } else {
-                    Choice::builder()
-                        .index(0)
-                        .delta(Message::default())
-                        .logprobs(None)
-                        .finish_reason("stop")
-                        .build()
-                };
+                }
+                CompletionKind::Text => Choice::builder()
+                    .index(index)
+                    .text(choice.content)
+                    .logprobs(None)
+                    .finish_reason(choice.finish_reason)
+                    .build(),
+            })
+            .collect();
 
-                let chat_completion = ChatCompletion::builder()
-                    .id(body.id)
-                    .model(&raw_model)
-                    .object("chat.completion.chunk")
-                    .created(body.created)
-                    .choices(vec![choice])
-                    .build();
+        let chat_completion = ChatCompletion::builder()
+            .id(id)
+            .model(&self.model)
+            .object(self.kind.object(false))
+            .created(created)
+            .choices(choices)
+            .usage(usage)
+            .build();
 
-                Event::default()
-                    .json_data(chat_completion)
-                    .map_err(Error::new)
-            },
-            |event| Ok(Event::default().data(event.data)),
-        );
-        Ok(Sse::new(sse_stream).into_response())
+        Ok(Json(chat_completion).into_response())
     }
+}
 
-    async fn into_single_response(self) -> crate::Result<Response> {
-        let mut id = None;
-        let mut created = None;
-        let mut model = None;
-        let mut content = String::new();
-
-        process_stream(self.resp, |body| {
-            if id.is_none() {
-                id = Some(body.id);
-            }
-            if created.is_none() {
-                created = Some(body.created);
+/// Read one upstream reply to the end, applying the output limits
+async fn single_choice(
+    resp: reqwest::Response,
+    model: &str,
+    max_tokens: Option<u32>,
+    stop: Vec<String>,
+    tools: Vec<Tool>,
+) -> crate::Result<SingleChoice> {
+    let mut id = None;
+    let mut created = None;
+    let mut detector = ToolCallDetector::new(tools);
+    let mut output = OutputLimit::new(model, max_tokens, stop);
+    let mut truncated = None;
+
+    process_stream_until(resp, |body| {
+        if id.is_none() {
+            id = Some(body.id);
+        }
+        if created.is_none() {
+            created = Some(body.created);
+        }
+        if let Some(message) = body.message
+            && let (_, Some(reason)) = output.push(&detector.push(&message))
+        {
+            truncated = Some(reason);
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    })
+    .await?;
+    let mut tool_calls = None;
+    if truncated.is_none() {
+        match detector.finish() {
+            Reply::ToolCalls(calls) => tool_calls = Some(calls),
+            Reply::Text(text) => {
+                truncated = match output.push(&text) {
+                    (_, Some(reason)) => Some(reason),
+                    _ => output.flush().1,
+                }
             }
-            if model.is_none() {
-                model = Some(body.model);
+        }
+    }
+
+    let (content, finish_reason) = match &tool_calls {
+        Some(calls) => (tools::render_calls(calls), "tool_calls"),
+        None => (output.text().to_owned(), truncated.unwrap_or("stop")),
+    };
+    Ok(SingleChoice {
+        id,
+        created,
+        content,
+        tool_calls,
+        finish_reason,
+        // a cut reply differs from what the upstream session holds
+        complete: truncated.is_none(),
+    })
+}
+
+/// Streamed `text_completion` chunk
+fn text_chunk(
+    model: &str,
+    id: String,
+    created: u64,
+    index: usize,
+    text: String,
+    finish_reason: impl Into<Option<&'static str>>,
+) -> EventResult {
+    let completion = ChatCompletion::builder()
+        .id(id)
+        .model(model)
+        .object(CompletionKind::Text.object(true))
+        .created(created)
+        .choices(vec![
+            Choice::builder()
+                .index(index)
+                .text(text)
+                .logprobs(None)
+                .finish_reason(finish_reason)
+                .build(),
+        ])
+        .build();
+    Event::default().json_data(completion).map_err(Error::new)
+}
+
+/// Upstream reply read to the end
+pub struct UpstreamReply {
+    pub id: String,
+    pub created: u64,
+    pub model: Option<String>,
+    pub text: String,
+}
+
+impl UpstreamReply {
+    pub async fn read(resp: reqwest::Response) -> crate::Result<Self> {
+        let mut reply = Self {
+            id: String::new(),
+            created: now(),
+            model: None,
+            text: String::new(),
+        };
+        let mut first = true;
+        process_stream(resp, |body| {
+            if std::mem::take(&mut first) {
+                reply.id = body.id;
+                reply.created = body.created;
+                reply.model = body.model;
             }
             if let Some(message) = body.message {
-                content.push_str(&message);
+                reply.text.push_str(&message);
             }
         })
-        .await;
-
-        let chat_completion = ChatCompletion::builder()
-            .id(id)
-            .model(&self.model)
-            .object("chat.completion")
-            .created(created)
-            .choices(vec![
-                Choice::builder()
-                    .index(0)
-                    .message(
-                        Message::builder()
-                            .role(Role::Assistant)
-                            .content(Content::Text(content))
-                            .build(),
-                    )
-                    .logprobs(None)
-                    .finish_reason("stop")
-                    .build(),
-            ])
-            .usage(
-                Usage::builder()
-                    .completion_tokens(0)
-                    .prompt_tokens(0)
-                    .total_tokens(0)
-                    .build(),
-            )
-            .build();
+        .await?;
+        Ok(reply)
+    }
 
-        Ok(Json(chat_completion).into_response())
+    /// Upstream response carrying `text` as this reply, for processing
+    /// after the original body has been consumed
+    pub fn replay(&self, text: &str) -> reqwest::Response {
+        let frame = serde_json::json!({
+            "message": text,
+            "created": self.created,
+            "id": self.id,
+            "model": self.model,
+        });
+        let body = format!("data: {frame}\n\ndata: [DONE]\n\n");
+        let resp = axum::http::Response::builder()
+            .header(CONTENT_TYPE, "text/event-stream")
+            .body(body)
+            .unwrap_or_default();
+        reqwest::Response::from(resp)
     }
 }
 
-async fn process_stream<H>(resp: reqwest::Response, mut handler: H)
+/// Feed upstream completions to `handler` until the stream is terminated by
+/// `[DONE]`, failing when it breaks off or reports an error
+pub async fn process_stream<H>(resp: reqwest::Response, mut handler: H) -> crate::Result<()>
 where
     H: FnMut(DuckChatCompletion),
 {
-    let mut event_source = resp.bytes_stream().eventsource();
-    while let Some(event_result) = event_source.next().await {
-        match event_result {
-            Ok(event) => {
-                if event.data == "[DONE]" {
-                    break;
-                }
-                match serde_json::from_str::<DuckChatCompletion>(&event.data) {
-                    Ok(body) => handler(body),
-                    Err(err) => {
-                        tracing::warn!("failed to parse upstream body: {err}");
+    process_stream_until(resp, |body| {
+        handler(body);
+        ControlFlow::Continue(())
+    })
+    .await
+}
+
+/// Like [`process_stream`], but `handler` may end the reply early, which
+/// drops the upstream body. An early end counts as terminated.
+pub async fn process_stream_until<H>(resp: reqwest::Response, mut handler: H) -> crate::Result<()>
+where
+    H: FnMut(DuckChatCompletion) -> ControlFlow<()>,
+{
+    let mut read = UpstreamRead::default();
+    let mut event_source = read.events(resp);
+    let result = 'read: {
+        while let Some(event_result) = event_source.next().await {
+            let event = match event_result {
+                Ok(event) => event,
+                Err(err) => break 'read Err(crate::Error::UpstreamInterrupted(err.to_string())),
+            };
+            if event.data == "[DONE]" {
+                break 'read Ok(());
+            }
+            match upstream_completion(&event.data) {
+                Ok(Some(body)) => {
+                    if handler(body).is_break() {
+                        break 'read Ok(());
                     }
                 }
+                Ok(None) => {}
+                Err(err) => break 'read Err(err),
             }
-            Err(err) => {
-                tracing::warn!("failed read upstream bytes stream: {err}")
-            }
+        }
+        Err(crate::Error::UpstreamInterrupted(UNTERMINATED.to_owned()))
+    };
+    read.finish();
+    if let Err(err) = &result {
+        tracing::warn!("upstream reply failed: {err}");
+    }
+    result
+}
+
+const UNTERMINATED: &str = "the stream ended before [DONE]";
+
+/// Completion of an upstream event, `None` for a malformed one, which is
+/// skipped, or the error an upstream error event reports
+fn upstream_completion(data: &str) -> crate::Result<Option<DuckChatCompletion>> {
+    if let Ok(event) = serde_json::from_str::<DuckChatError>(data)
+        && event.action == "error"
+    {
+        return Err(crate::Error::upstream_event(event));
+    }
+    match serde_json::from_str(data) {
+        Ok(body) => Ok(Some(body)),
+        Err(err) => {
+            tracing::warn!("failed to parse upstream body: {err}");
+            Ok(None)
+        }
+    }
+}
+
+/// Bytes read from an upstream body. A reply dropped before it finished
+/// reading, as when the client disconnects, cancels the upstream request and
+/// logs how much of it had been read.
+#[derive(Default)]
+struct UpstreamRead {
+    bytes: Arc<AtomicUsize>,
+    finished: bool,
+}
+
+impl UpstreamRead {
+    /// Events of the upstream body, counting the bytes read
+    fn events(
+        &self,
+        resp: reqwest::Response,
+    ) -> impl Stream<Item = Result<eventsource_stream::Event, EventStreamError<reqwest::Error>>> + use<>
+    {
+        let bytes = self.bytes.clone();
+        resp.bytes_stream()
+            .inspect(move |chunk| {
+                if let Ok(chunk) = chunk {
+                    bytes.fetch_add(chunk.len(), Ordering::Relaxed);
+                }
+            })
+            .eventsource()
+    }
+
+    /// The reply is done with the upstream body
+    fn finish(&mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for UpstreamRead {
+    fn drop(&mut self) {
+        if !self.finished {
+            let bytes = self.bytes.load(Ordering::Relaxed);
+            tracing::info!(
+                read_bytes = bytes,
+                "reply dropped before the upstream finished, cancelled after reading {bytes} bytes"
+            );
         }
     }
 }
 
-fn process_stream_with_chunk<S, E>(
+/// Stream of the items `handler` makes of upstream completions, ending
+/// with the item of `end_handler` at `[DONE]`. A reply that breaks off or
+/// reports an error ends with the item of `error_handler`.
+pub fn process_stream_with_chunk<T, S, E, F>(
     resp: reqwest::Response,
     mut handler: S,
     end_handler: E,
-) -> impl Stream<Item = EventResult>
+    error_handler: F,
+) -> impl Stream<Item = T>
 where
-    S: FnMut(DuckChatCompletion) -> EventResult,
-    E: FnOnce(eventsource_stream::Event) -> EventResult,
+    S: FnMut(DuckChatCompletion) -> T,
+    E: FnOnce(eventsource_stream::Event) -> T,
+    F: FnOnce(crate::Error) -> T,
+{
+    process_stream_with_chunk_until(
+        resp,
+        move |body| ControlFlow::Continue(handler(body)),
+        end_handler,
+        error_handler,
+    )
+}
+
+/// Like [`process_stream_with_chunk`], but `handler` may end the reply
+/// early with its last item, which drops the upstream body. `end_handler`
+/// then runs as if the upstream had sent `[DONE]`. A reply that breaks off
+/// or reports an error ends with the item of `error_handler`.
+pub fn process_stream_with_chunk_until<T, S, E, F>(
+    resp: reqwest::Response,
+    mut handler: S,
+    end_handler: E,
+    error_handler: F,
+) -> impl Stream<Item = T>
+where
+    S: FnMut(DuckChatCompletion) -> ControlFlow<T, T>,
+    E: FnOnce(eventsource_stream::Event) -> T,
+    F: FnOnce(crate::Error) -> T,
 {
-    let mut event_source = resp.bytes_stream().eventsource();
     async_stream::stream! {
-        while let Some(event_result) = event_source.next().await {
-            match event_result {
-                Ok(event) => {
-                    if event.data == "[DONE]" {
-                        yield end_handler(event);
-                        break;
-                    }
-                    match serde_json::from_str::<DuckChatCompletion>(&event.data) {
-                        Ok(body) => yield handler(body),
-                        Err(err) => {
-                            tracing::warn!("failed to parse upstream body: {err}");
-                        }
-                    }
+        let mut read = UpstreamRead::default();
+        let mut event_source = read.events(resp);
+        let error = 'read: {
+            while let Some(event_result) = event_source.next().await {
+                let event = match event_result {
+                    Ok(event) => event,
+                    Err(err) => break 'read Some(crate::Error::UpstreamInterrupted(err.to_string())),
+                };
+                if event.data == "[DONE]" {
+                    yield end_handler(event);
+                    break 'read None;
                 }
-                Err(err) => {
-                    tracing::warn!("failed read upstream bytes stream: {err}")
+                match upstream_completion(&event.data) {
+                    Ok(Some(body)) => match handler(body) {
+                        ControlFlow::Continue(item) => yield item,
+                        ControlFlow::Break(item) => {
+                            yield item;
+                            yield end_handler(eventsource_stream::Event {
+                                data: "[DONE]".to_owned(),
+                                ..Default::default()
+                            });
+                            break 'read None;
+                        }
+                    },
+                    Ok(None) => {}
+                    Err(err) => break 'read Some(err),
                 }
             }
+            Some(crate::Error::UpstreamInterrupted(UNTERMINATED.to_owned()))
+        };
+        read.finish();
+        if let Some(err) = error {
+            tracing::warn!("upstream reply failed: {err}");
+            yield error_handler(err);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::test_util::{body_text, sse_payloads, upstream_response};
+    use crate::mock::{Frame, Scenario, Script};
+    use serde_json::Value;
+
+    async fn process(script: Script, stream: bool) -> crate::Result<Response> {
+        ChatProcess::builder()
+            .resp(upstream_response(script).await)
+            .stream(Some(stream))
+            .model("gpt-4o-mini".to_owned())
+            .build()
+            .into_response()
+            .await
+    }
+
+    fn stream_content(body: &str) -> String {
+        sse_payloads(body)
+            .into_iter()
+            .filter(|data| *data != "[DONE]")
+            .map(|data| serde_json::from_str::<Value>(data).unwrap())
+            .filter_map(|chunk| {
+                chunk["choices"][0]["delta"]["content"]
+                    .as_str()
+                    .map(ToOwned::to_owned)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn single_response_joins_messages() {
+        let resp = process(Scenario::Normal.into(), false).await.unwrap();
+        let body: Value = serde_json::from_str(&body_text(resp).await).unwrap();
+        assert_eq!(body["object"], "chat.completion");
+        assert_eq!(body["model"], "gpt-4o-mini");
+        assert_eq!(body["choices"][0]["message"]["role"], "assistant");
+        assert_eq!(body["choices"][0]["message"]["content"], "Hello, world!");
+        assert_eq!(body["choices"][0]["finish_reason"], "stop");
+    }
+
+    #[tokio::test]
+    async fn stream_response_emits_chunks_and_done() {
+        let resp = process(Scenario::Normal.into(), true).await.unwrap();
+        let body = body_text(resp).await;
+        let payloads = sse_payloads(&body);
+        assert_eq!(payloads.last(), Some(&"[DONE]"));
+
+        let first: Value = serde_json::from_str(payloads[0]).unwrap();
+        assert_eq!(first["object"], "chat.completion.chunk");
+        assert_eq!(first["choices"][0]["delta"]["role"], "assistant");
+        let second: Value = serde_json::from_str(payloads[1]).unwrap();
+        assert!(second["choices"][0]["delta"]["role"].is_null());
+
+        assert_eq!(stream_content(&body), "Hello, world!");
+    }
+
+    #[tokio::test]
+    async fn single_response_estimates_usage() {
+        let resp = ChatProcess::builder()
+            .resp(upstream_response(Scenario::Normal.into()).await)
+            .stream(Some(false))
+            .model("gpt-4o-mini".to_owned())
+            .prompt_tokens(10)
+            .build()
+            .into_response()
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_str(&body_text(resp).await).unwrap();
+        assert_eq!(
+            body["usage"],
+            serde_json::json!({"prompt_tokens": 10, "completion_tokens": 4, "total_tokens": 14})
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_response_ends_with_usage_chunk() {
+        let resp = ChatProcess::builder()
+            .resp(upstream_response(Scenario::Normal.into()).await)
+            .stream(Some(true))
+            .model("gpt-4o-mini".to_owned())
+            .prompt_tokens(10)
+            .include_usage(true)
+            .build()
+            .into_response()
+            .await
+            .unwrap();
+        let body = body_text(resp).await;
+        let payloads = sse_payloads(&body);
+        assert_eq!(payloads.last(), Some(&"[DONE]"));
+
+        let usage: Value = serde_json::from_str(payloads[payloads.len() - 2]).unwrap();
+        assert_eq!(usage["choices"], serde_json::json!([]));
+        assert_eq!(usage["usage"]["prompt_tokens"], 10);
+        assert_eq!(usage["usage"]["completion_tokens"], 4);
+        let chunks = &payloads[..payloads.len() - 2];
+        assert!(
+            chunks
+                .iter()
+                .all(|data| { serde_json::from_str::<Value>(data).unwrap()["usage"].is_null() })
+        );
+    }
+
+    #[tokio::test]
+    async fn max_tokens_truncates_reply() {
+        let limited = |stream| async move {
+            let resp = ChatProcess::builder()
+                .resp(upstream_response(Scenario::Normal.into()).await)
+                .stream(Some(stream))
+                .model("gpt-4o-mini".to_owned())
+                .max_tokens(Some(2))
+                .build()
+                .into_response()
+                .await
+                .unwrap();
+            body_text(resp).await
+        };
+
+        let body: Value = serde_json::from_str(&limited(false).await).unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "Hello,");
+        assert_eq!(body["choices"][0]["finish_reason"], "length");
+        assert_eq!(body["usage"]["completion_tokens"], 2);
+
+        let body = limited(true).await;
+        assert_eq!(stream_content(&body), "Hello,");
+        let payloads = sse_payloads(&body);
+        assert_eq!(payloads.last(), Some(&"[DONE]"));
+        let last: Value = serde_json::from_str(payloads[payloads.len() - 2]).unwrap();
+        assert_eq!(last["choices"][0]["finish_reason"], "length");
+    }
+
+    #[test]
+    fn output_limit_cuts_inside_delta() {
+        let mut output = OutputLimit::new("gpt-4o-mini", Some(3), Vec::new());
+        assert_eq!(output.push("Hello"), ("Hello".to_owned(), None));
+        assert_eq!(
+            output.push(", world and more"),
+            (", world".to_owned(), Some("length"))
+        );
+        assert_eq!(output.text(), "Hello, world");
+
+        let mut output = OutputLimit::new("gpt-4o-mini", None, Vec::new());
+        assert_eq!(
+            output.push("Hello, world"),
+            ("Hello, world".to_owned(), None)
+        );
+    }
+
+    #[test]
+    fn output_limit_detects_split_stop_sequence() {
+        let stop = vec!["world".to_owned(), "END".to_owned()];
+        let mut output = OutputLimit::new("gpt-4o-mini", None, stop);
+        assert_eq!(output.push("Hello, wor"), ("Hello, ".to_owned(), None));
+        assert_eq!(output.push("ld!"), (String::new(), Some("stop")));
+        assert_eq!(output.text(), "Hello, ");
+
+        let mut output = OutputLimit::new("gpt-4o-mini", None, vec!["END".to_owned()]);
+        assert_eq!(output.push("Hello E"), ("Hello ".to_owned(), None));
+        assert_eq!(output.push("N"), (String::new(), None));
+        assert_eq!(output.push("ds"), ("ENds".to_owned(), None));
+        assert_eq!(output.push("E"), (String::new(), None));
+        assert_eq!(output.flush(), ("E".to_owned(), None));
+    }
+
+    #[test]
+    fn partial_match_respects_char_boundaries() {
+        assert_eq!(partial_match("Hello wo", "world"), 2);
+        assert_eq!(partial_match("Hello", "world"), 0);
+        assert_eq!(partial_match("caf\u{e9}", "\u{e9}t\u{e9}"), 2);
+        assert_eq!(partial_match("w", "w"), 0);
+    }
+
+    #[tokio::test]
+    async fn stop_sequence_ends_reply() {
+        let stopped = |stream, stop: &str| {
+            let stop = vec![stop.to_owned()];
+            async move {
+                let resp = ChatProcess::builder()
+                    .resp(upstream_response(Scenario::Normal.into()).await)
+                    .stream(Some(stream))
+                    .model("gpt-4o-mini".to_owned())
+                    .stop(stop)
+                    .build()
+                    .into_response()
+                    .await
+                    .unwrap();
+                body_text(resp).await
+            }
+        };
+
+        // ", w" spans the ", " and "world" messages
+        let body: Value = serde_json::from_str(&stopped(false, ", w").await).unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "Hello");
+        assert_eq!(body["choices"][0]["finish_reason"], "stop");
+
+        let body = stopped(true, ", w").await;
+        assert_eq!(stream_content(&body), "Hello");
+        let payloads = sse_payloads(&body);
+        let last: Value = serde_json::from_str(payloads[payloads.len() - 2]).unwrap();
+        assert_eq!(last["choices"][0]["finish_reason"], "stop");
+
+        // held back text is released when the reply ends without a match
+        let body = stopped(true, "!!").await;
+        assert_eq!(stream_content(&body), "Hello, world!");
+    }
+
+    #[tokio::test]
+    async fn tool_call_reply_becomes_tool_calls() {
+        let script = || {
+            Script::messages([
+                "{\"tool_calls\": [{\"name\": ",
+                "\"get_weather\", \"arguments\": {\"city\": \"Paris\"}}]}",
+            ])
+        };
+        let tool: Tool = serde_json::from_value(serde_json::json!({
+            "type": "function",
+            "function": {"name": "get_weather"},
+        }))
+        .unwrap();
+        let with_tools = |stream| {
+            let tools = vec![tool.clone()];
+            async move {
+                let resp = ChatProcess::builder()
+                    .resp(upstream_response(script()).await)
+                    .stream(Some(stream))
+                    .model("gpt-4o-mini".to_owned())
+                    .tools(tools)
+                    .build()
+                    .into_response()
+                    .await
+                    .unwrap();
+                body_text(resp).await
+            }
+        };
+
+        let body: Value = serde_json::from_str(&with_tools(false).await).unwrap();
+        let message = &body["choices"][0]["message"];
+        assert!(message["content"].is_null());
+        assert_eq!(message["tool_calls"][0]["type"], "function");
+        assert_eq!(message["tool_calls"][0]["function"]["name"], "get_weather");
+        assert_eq!(
+            message["tool_calls"][0]["function"]["arguments"],
+            r#"{"city":"Paris"}"#
+        );
+        assert!(message["tool_calls"][0].get("index").is_none());
+        assert_eq!(body["choices"][0]["finish_reason"], "tool_calls");
+
+        let body = with_tools(true).await;
+        let chunks: Vec<Value> = sse_payloads(&body)
+            .into_iter()
+            .filter(|data| *data != "[DONE]")
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        assert_eq!(chunks.len(), 2);
+        let delta = &chunks[0]["choices"][0]["delta"];
+        assert_eq!(delta["role"], "assistant");
+        assert_eq!(delta["tool_calls"][0]["index"], 0);
+        assert_eq!(delta["tool_calls"][0]["function"]["name"], "get_weather");
+        assert_eq!(chunks[1]["choices"][0]["finish_reason"], "tool_calls");
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_are_skipped() {
+        let resp = process(Scenario::Malformed.into(), false).await.unwrap();
+        let body: Value = serde_json::from_str(&body_text(resp).await).unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "Hello, world");
+
+        let resp = process(Scenario::Malformed.into(), true).await.unwrap();
+        assert_eq!(stream_content(&body_text(resp).await), "Hello, world");
+    }
+
+    /// Payloads of a streamed reply cut off by an error: its content, its
+    /// finish reason and the error object
+    fn stream_error(body: &str) -> (String, Value, Value) {
+        let payloads = sse_payloads(body);
+        assert!(!payloads.contains(&"[DONE]"));
+        let chunks: Vec<Value> = payloads
+            .iter()
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        let (error, chunks) = chunks.split_last().unwrap();
+        let finish_reason = chunks.last().unwrap()["choices"][0]["finish_reason"].clone();
+        (stream_content(body), finish_reason, error["error"].clone())
+    }
+
+    #[tokio::test]
+    async fn disconnect_is_an_error() {
+        let result = process(Scenario::Disconnect.into(), false).await;
+        assert!(matches!(result, Err(crate::Error::UpstreamInterrupted(_))));
+
+        let resp = process(Scenario::Disconnect.into(), true).await.unwrap();
+        let (content, finish_reason, error) = stream_error(&body_text(resp).await);
+        assert_eq!(content, "Hello, wor");
+        assert_eq!(finish_reason, "error");
+        assert_eq!(error["type"], "server_error");
+        assert!(
+            error["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("upstream reply broke off")
+        );
+    }
+
+    #[tokio::test]
+    async fn upstream_error_event_is_an_error() {
+        let script = || Script {
+            frames: vec![
+                Frame::Message("Hello".to_owned()),
+                Frame::Raw(
+                    r#"{"action":"error","status":429,"type":"ERR_CONVERSATION_LIMIT"}"#.to_owned(),
+                ),
+            ],
+            ..Scenario::Normal.into()
+        };
+        let result = process(script(), false).await;
+        assert!(matches!(
+            result,
+            Err(crate::Error::UpstreamRateLimited { message, .. })
+                if message == "ERR_CONVERSATION_LIMIT"
+        ));
+
+        let resp = process(script(), true).await.unwrap();
+        let (content, finish_reason, error) = stream_error(&body_text(resp).await);
+        assert_eq!(content, "Hello");
+        assert_eq!(finish_reason, "error");
+        assert_eq!(error["type"], "rate_limit_error");
+        assert_eq!(error["code"], "rate_limit_exceeded");
+    }
+
+    #[tokio::test]
+    async fn upstream_error_status_is_error() {
+        let result = process(Scenario::Error.into(), true).await;
+        assert!(matches!(
+            result,
+            Err(crate::Error::UpstreamUnavailable { .. })
+        ));
+    }
+}
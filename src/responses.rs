@@ -0,0 +1,678 @@
+//! OpenAI Responses API (`/v1/responses`) on top of the chat pipeline
+
+use crate::auth;
+use crate::error::Error;
+use crate::model::{
+    ChatRequest, Content, ContentItem, DuckChatCompletion, Message, Role, gen_id, now,
+};
+use crate::process::{
+    CompletionHook, EventResult, process_stream, process_stream_with_chunk, sse_response,
+};
+use crate::serve::AppState;
+use crate::session::conversation_id;
+use crate::store::TtlStore;
+use crate::tokenizer;
+use axum::{
+    Json,
+    extract::State,
+    http::HeaderMap,
+    response::{IntoResponse, Response, sse::Event},
+};
+use axum_extra::{
+    TypedHeader,
+    extract::WithRejection,
+    headers::{Authorization, authorization::Bearer},
+};
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+pub type ResponseStore = TtlStore<StoredResponse>;
+
+/// Conversation behind a stored response, continued by `previous_response_id`
+#[derive(Clone)]
+pub struct StoredResponse {
+    /// Input and output messages, without instructions
+    pub messages: Vec<Message>,
+}
+
+// ==================== Request Body ====================
+#[derive(Debug, Deserialize)]
+pub struct ResponsesRequest {
+    pub model: String,
+    pub input: Input,
+    #[serde(default)]
+    pub instructions: Option<String>,
+    #[serde(default)]
+    pub previous_response_id: Option<String>,
+    #[serde(default)]
+    pub stream: Option<bool>,
+    #[serde(default = "default_store")]
+    pub store: bool,
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+fn default_store() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Input {
+    Text(String),
+    Items(Vec<InputItem>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum InputItem {
+    Message(InputMessage),
+    /// Tool calls, reasoning and other items the upstream can't take
+    Other(serde::de::IgnoredAny),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InputMessage {
+    /// `message`, or absent for the short form
+    #[serde(rename = "type", default)]
+    pub r#type: Option<String>,
+    pub role: InputRole,
+    pub content: InputContent,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputRole {
+    User,
+    Assistant,
+    System,
+    Developer,
+}
+
+/// Text or parts; images, files and audio are left to the
+/// `unsupported_content` policy
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum InputContent {
+    Text(String),
+    Parts(Vec<ContentItem>),
+}
+
+impl From<InputContent> for Content {
+    fn from(content: InputContent) -> Self {
+        match content {
+            InputContent::Text(text) => Content::Text(text),
+            InputContent::Parts(parts) => Content::Vec(parts),
+        }
+    }
+}
+
+impl Input {
+    /// Input as upstream messages
+    fn into_messages(self) -> Vec<Message> {
+        let items = match self {
+            Input::Text(text) => return vec![user_message(text)],
+            Input::Items(items) => items,
+        };
+        items
+            .into_iter()
+            .filter_map(|item| match item {
+                InputItem::Message(message)
+                    if message.r#type.as_deref().is_none_or(|t| t == "message") =>
+                {
+                    let role = match message.role {
+                        InputRole::Assistant => Role::Assistant,
+                        InputRole::User => Role::User,
+                        InputRole::System | InputRole::Developer => Role::System.upstream(),
+                    };
+                    Some(
+                        Message::builder()
+                            .role(role)
+                            .content(Content::from(message.content))
+                            .build(),
+                    )
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+fn user_message(text: String) -> Message {
+    Message::builder()
+        .role(Role::User)
+        .content(Content::Text(text))
+        .build()
+}
+
+// ==================== Response Body ====================
+/// Fields shared by every snapshot of a response object
+struct ResponseMeta {
+    id: String,
+    item_id: String,
+    created_at: u64,
+    model: String,
+    instructions: Option<String>,
+    previous_response_id: Option<String>,
+    /// Estimated prompt tokens
+    input_tokens: u32,
+}
+
+impl ResponseMeta {
+    /// Response object with `status`, carrying `text` once there is output
+    fn object(&self, status: &str, text: Option<&str>) -> Value {
+        let output: Vec<Value> = text
+            .map(|text| self.message_item(status, Some(text)))
+            .into_iter()
+            .collect();
+        let output_tokens = text.map_or(0, |text| tokenizer::count_text(&self.model, text));
+        json!({
+            "id": self.id,
+            "object": "response",
+            "created_at": self.created_at,
+            "status": status,
+            "error": null,
+            "incomplete_details": null,
+            "instructions": self.instructions,
+            "model": self.model,
+            "output": output,
+            "previous_response_id": self.previous_response_id,
+            "usage": {
+                "input_tokens": self.input_tokens,
+                "output_tokens": output_tokens,
+                "total_tokens": self.input_tokens + output_tokens,
+            },
+        })
+    }
+
+    /// Response object of a reply that failed with `err` after `text`
+    fn failed(&self, text: &str, err: Error) -> Value {
+        let (_, error, _) = err.parts();
+        let error = serde_json::to_value(error).unwrap_or_default();
+        let mut object = self.object("failed", Some(text));
+        object["output"][0]["status"] = json!("incomplete");
+        object["error"] = json!({
+            "code": error.get("code").unwrap_or(&error["type"]),
+            "message": error["message"],
+        });
+        object
+    }
+
+    fn message_item(&self, status: &str, text: Option<&str>) -> Value {
+        let content: Vec<Value> = text.map(output_text).into_iter().collect();
+        json!({
+            "type": "message",
+            "id": self.item_id,
+            "status": status,
+            "role": "assistant",
+            "content": content,
+        })
+    }
+}
+
+fn output_text(text: &str) -> Value {
+    json!({"type": "output_text", "text": text, "annotations": []})
+}
+
+pub async fn responses(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    headers: HeaderMap,
+    WithRejection(Json(req), _): WithRejection<Json<ResponsesRequest>, Error>,
+) -> crate::Result<Response> {
+    let key = state.valid_key(bearer)?;
+
+    let mut history = match &req.previous_response_id {
+        Some(id) => {
+            let previous = state
+                .responses
+                .get(&auth::scoped_id(key, id))
+                .ok_or_else(|| Error::InvalidRequest {
+                    message: format!("Previous response with id '{id}' not found."),
+                    param: Some("previous_response_id".to_owned()),
+                })?;
+            previous.messages
+        }
+        None => Vec::new(),
+    };
+    history.extend(req.input.into_messages());
+
+    // instructions apply to this response only and are not carried over
+    let messages = req
+        .instructions
+        .clone()
+        .map(user_message)
+        .into_iter()
+        .chain(history.iter().cloned())
+        .collect();
+
+    let mut body = ChatRequest::builder()
+        .model(&req.model)
+        .messages(messages)
+        .stream(req.stream)
+        .user(req.user)
+        .build();
+    state.resolve_request(key, &mut body)?;
+    let conversation = conversation_id(&headers, body.user.as_deref(), key);
+
+    let meta = ResponseMeta {
+        id: gen_id("resp"),
+        item_id: gen_id("msg"),
+        created_at: now(),
+        model: body.model.clone(),
+        instructions: req.instructions,
+        previous_response_id: req.previous_response_id,
+        input_tokens: tokenizer::count_messages(&body.model, &body.messages),
+    };
+
+    let store = req
+        .store
+        .then(|| (state.responses.clone(), auth::scoped_id(key, &meta.id)));
+    let stream = body.stream.unwrap_or_default();
+    let keep_alive = state.sse_keep_alive;
+    let start = async move {
+        let (resp, session_hook) = crate::route::forward(&state, &mut body, conversation).await?;
+        let on_complete: CompletionHook = Box::new(move |reply| {
+            if let Some(hook) = session_hook {
+                hook(reply.clone());
+            }
+            if let Some((store, id)) = store {
+                history.push(
+                    Message::builder()
+                        .role(Role::Assistant)
+                        .content(Content::Text(reply))
+                        .build(),
+                );
+                store.insert(id, StoredResponse { messages: history });
+            }
+        });
+        Ok((resp, on_complete))
+    };
+
+    if stream {
+        // show activity while the token and upstream requests are in flight
+        return Ok(stream_response(start, meta, keep_alive));
+    }
+    let (resp, on_complete) = start.await?;
+    single_response(resp, meta, on_complete).await
+}
+
+fn event(data: Value) -> EventResult {
+    let name = data["type"].as_str().unwrap_or("response").to_owned();
+    Event::default().event(name).json_data(data)
+}
+
+/// Stream a response whose upstream request `start` makes once the
+/// response is created
+fn stream_response<F>(start: F, meta: ResponseMeta, keep_alive: Option<Duration>) -> Response
+where
+    F: Future<Output = crate::Result<(reqwest::Response, CompletionHook)>> + Send + 'static,
+{
+    let meta = Arc::new(meta);
+    let opening = vec![
+        json!({"type": "response.created", "response": meta.object("in_progress", None)}),
+        json!({"type": "response.in_progress", "response": meta.object("in_progress", None)}),
+        json!({
+            "type": "response.output_item.added",
+            "output_index": 0,
+            "item": meta.message_item("in_progress", None),
+        }),
+        json!({
+            "type": "response.content_part.added",
+            "item_id": meta.item_id,
+            "output_index": 0,
+            "content_index": 0,
+            "part": output_text(""),
+        }),
+    ];
+
+    let events = async_stream::stream! {
+        for data in opening {
+            yield data;
+        }
+        match start.await {
+            Ok((resp, on_complete)) => {
+                let mut events = std::pin::pin!(reply_events(resp, meta.clone(), on_complete));
+                while let Some(data) = events.next().await {
+                    yield data;
+                }
+            }
+            Err(err) => yield json!({"type": "response.failed", "response": meta.failed("", err)}),
+        }
+    };
+    let sse_stream = events.enumerate().map(|(sequence_number, mut data)| {
+        data["sequence_number"] = json!(sequence_number);
+        event(data)
+    });
+    sse_response(sse_stream, keep_alive)
+}
+
+/// Events of the upstream reply, after the opening ones
+fn reply_events(
+    resp: reqwest::Response,
+    meta: Arc<ResponseMeta>,
+    on_complete: CompletionHook,
+) -> impl Stream<Item = Value> {
+    let reply = Arc::new(Mutex::new(String::new()));
+    let reply_end = reply.clone();
+    let reply_failed = reply.clone();
+    let meta_end = meta.clone();
+    let item_id = meta.item_id.clone();
+
+    process_stream_with_chunk(
+        resp,
+        move |body: DuckChatCompletion| match body.message {
+            Some(delta) => {
+                reply
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push_str(&delta);
+                vec![json!({
+                    "type": "response.output_text.delta",
+                    "item_id": item_id,
+                    "output_index": 0,
+                    "content_index": 0,
+                    "delta": delta,
+                })]
+            }
+            None => Vec::new(),
+        },
+        move |_| {
+            let meta = meta_end;
+            let text = reply_end
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone();
+            let events = vec![
+                json!({
+                    "type": "response.output_text.done",
+                    "item_id": meta.item_id,
+                    "output_index": 0,
+                    "content_index": 0,
+                    "text": text,
+                }),
+                json!({
+                    "type": "response.content_part.done",
+                    "item_id": meta.item_id,
+                    "output_index": 0,
+                    "content_index": 0,
+                    "part": output_text(&text),
+                }),
+                json!({
+                    "type": "response.output_item.done",
+                    "output_index": 0,
+                    "item": meta.message_item("completed", Some(&text)),
+                }),
+                json!({
+                    "type": "response.completed",
+                    "response": meta.object("completed", Some(&text)),
+                }),
+            ];
+            on_complete(text);
+            events
+        },
+        move |err| {
+            let text = reply_failed.lock().unwrap_or_else(PoisonError::into_inner);
+            vec![json!({"type": "response.failed", "response": meta.failed(&text, err)})]
+        },
+    )
+    .flat_map(futures_util::stream::iter)
+}
+
+async fn single_response(
+    resp: reqwest::Response,
+    meta: ResponseMeta,
+    on_complete: CompletionHook,
+) -> crate::Result<Response> {
+    let mut text = String::new();
+    process_stream(resp, |body| {
+        if let Some(message) = body.message {
+            text.push_str(&message);
+        }
+    })
+    .await?;
+
+    on_complete(text.clone());
+    Ok(Json(meta.object("completed", Some(&text))).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Scenario;
+    use crate::mock::test_util::{post_json, spawn_proxy, spawn_proxy_with, sse_payloads};
+
+    #[test]
+    fn input_items_map_to_messages() {
+        let input: Input = serde_json::from_value(json!([
+            {"role": "developer", "content": "Be brief"},
+            {"type": "message", "role": "user", "content": [
+                {"type": "input_text", "text": "Hi"},
+                {"type": "input_image", "image_url": "https://example.com/a.png"},
+            ]},
+            {"type": "function_call_output", "call_id": "call_1", "output": "{}"},
+            {"role": "assistant", "content": [{"type": "output_text", "text": "Hello"}]},
+        ]))
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(input.into_messages()).unwrap(),
+            json!([
+                {"role": "user", "content": "Be brief"},
+                {"role": "user", "content": [
+                    {"type": "input_text", "text": "Hi"},
+                    {"type": "input_image"},
+                ]},
+                {"role": "assistant", "content": [{"type": "output_text", "text": "Hello"}]},
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn unsupported_content_policy_applies() {
+        let (addr, mock) = spawn_proxy_with(Scenario::Normal.into(), |config| {
+            config.unsupported_content = crate::model::UnsupportedContent::Reject
+        })
+        .await;
+        let resp = post_json(
+            addr,
+            "/v1/responses",
+            json!({"model": "gpt-4o-mini", "input": [{"role": "user", "content": [
+                {"type": "input_text", "text": "Describe"},
+                {"type": "input_image", "image_url": "https://example.com/a.png"},
+            ]}]}),
+        )
+        .await;
+        assert_eq!(resp.status(), 400);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["param"], "messages[0].content[1].type");
+        assert!(mock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn responses_single_response() {
+        let (addr, mock) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_json(
+            addr,
+            "/v1/responses",
+            json!({"model": "gpt-4o-mini", "instructions": "Be brief", "input": "Hi"}),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["object"], "response");
+        assert_eq!(body["status"], "completed");
+        assert_eq!(body["instructions"], "Be brief");
+        assert_eq!(body["output"][0]["content"][0]["text"], "Hello, world!");
+        assert_eq!(
+            mock.requests()[0].body["messages"][0]["content"],
+            "user:Be brief;\nuser:Hi;\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn responses_stream_events() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_json(
+            addr,
+            "/v1/responses",
+            json!({"model": "gpt-4o-mini", "input": "Hi", "stream": true}),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let body = resp.text().await.unwrap();
+        let events: Vec<Value> = sse_payloads(&body)
+            .into_iter()
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        assert_eq!(events[0]["type"], "response.created");
+        let last = events.last().unwrap();
+        assert_eq!(last["type"], "response.completed");
+        assert_eq!(
+            last["response"]["output"][0]["content"][0]["text"],
+            "Hello, world!"
+        );
+        let delta: String = events
+            .iter()
+            .filter(|e| e["type"] == "response.output_text.delta")
+            .map(|e| e["delta"].as_str().unwrap())
+            .collect();
+        assert_eq!(delta, "Hello, world!");
+        for (i, event) in events.iter().enumerate() {
+            assert_eq!(event["sequence_number"], i);
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_opens_before_the_upstream_answers() {
+        let (addr, _) = spawn_proxy(Scenario::RateLimited.into()).await;
+        let resp = post_json(
+            addr,
+            "/v1/responses",
+            json!({"model": "gpt-4o-mini", "input": "Hi", "stream": true}),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let body = resp.text().await.unwrap();
+        let events: Vec<Value> = sse_payloads(&body)
+            .into_iter()
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        assert_eq!(events[0]["type"], "response.created");
+        let last = events.last().unwrap();
+        assert_eq!(last["type"], "response.failed");
+        assert_eq!(last["response"]["error"]["code"], "rate_limit_exceeded");
+        assert_eq!(last["sequence_number"], events.len() - 1);
+    }
+
+    #[tokio::test]
+    async fn disconnect_is_an_error() {
+        let (addr, _) = spawn_proxy(Scenario::Disconnect.into()).await;
+        let resp = post_json(
+            addr,
+            "/v1/responses",
+            json!({"model": "gpt-4o-mini", "input": "Hi"}),
+        )
+        .await;
+        assert_eq!(resp.status(), 502);
+
+        let resp = post_json(
+            addr,
+            "/v1/responses",
+            json!({"model": "gpt-4o-mini", "input": "Hi", "stream": true}),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let body = resp.text().await.unwrap();
+        let last: Value = serde_json::from_str(sse_payloads(&body).last().unwrap()).unwrap();
+        assert_eq!(last["type"], "response.failed");
+        assert_eq!(last["response"]["status"], "failed");
+        assert_eq!(last["response"]["error"]["code"], "server_error");
+        assert!(!body.contains("response.completed"));
+    }
+
+    #[tokio::test]
+    async fn previous_response_id_continues_conversation() {
+        let (addr, mock) = spawn_proxy(Scenario::Normal.into()).await;
+        let first: Value = post_json(
+            addr,
+            "/v1/responses",
+            json!({"model": "gpt-4o-mini", "instructions": "Be brief", "input": "Hi"}),
+        )
+        .await
+        .json()
+        .await
+        .unwrap();
+        let resp = post_json(
+            addr,
+            "/v1/responses",
+            json!({
+                "model": "gpt-4o-mini",
+                "input": "Bye",
+                "previous_response_id": first["id"],
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(
+            mock.requests()[1].body["messages"][0]["content"],
+            "user:Hi;\nassistant:Hello, world!;\nuser:Bye;\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_previous_response_id_is_rejected() {
+        let (addr, mock) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_json(
+            addr,
+            "/v1/responses",
+            json!({"model": "gpt-4o-mini", "input": "Hi", "previous_response_id": "resp_x"}),
+        )
+        .await;
+        assert_eq!(resp.status(), 400);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["param"], "previous_response_id");
+        assert!(mock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn previous_response_id_is_scoped_to_the_key() {
+        let (addr, mock) = spawn_proxy_with(Scenario::Normal.into(), |config| {
+            config.api_keys = vec![crate::config::ApiKey {
+                name: "other".to_owned(),
+                key: Some("sk-other".to_owned()),
+                key_hash: None,
+                enabled: true,
+                models: Vec::new(),
+                expires_at: None,
+            }];
+        })
+        .await;
+        let first: Value = post_json(
+            addr,
+            "/v1/responses",
+            json!({"model": "gpt-4o-mini", "input": "Hi"}),
+        )
+        .await
+        .json()
+        .await
+        .unwrap();
+        let resp = reqwest::Client::new()
+            .post(format!("http://{addr}/v1/responses"))
+            .bearer_auth("sk-other")
+            .json(&json!({
+                "model": "gpt-4o-mini",
+                "input": "Bye",
+                "previous_response_id": first["id"],
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 400);
+        assert_eq!(mock.requests().len(), 1);
+    }
+}
@@ -1,12 +1,18 @@
 use crate::Result;
+use crate::auth;
+use crate::config::{ModelConfig, Upstream};
 use crate::error::Error::{self, MissingHeader};
+use crate::format::{self, ResponseFormat};
 use crate::hash::gen_request_hash;
-use crate::model::ChatRequest;
-use crate::process::ChatProcess;
+use crate::model::{ChatRequest, CompletionRequest, Content, Message, MessageMode, Role};
+use crate::process::{self, ChatProcess, CompletionHook, CompletionKind, UpstreamReply};
 use crate::serve::AppState;
+use crate::session::{Conversation, Session, conversation_id};
+use crate::tokenizer;
 use axum::{
     Json,
-    extract::State,
+    extract::{Path, State},
+    http::HeaderMap,
     response::{IntoResponse, Response},
 };
 use axum_extra::{
@@ -16,46 +22,18 @@ use axum_extra::{
 };
 use reqwest::{Client, header};
 
-const ORIGIN_API: &str = "https://duckduckgo.com";
-
 pub async fn models(
     State(state): State<AppState>,
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
 ) -> crate::Result<Response> {
-    state.valid_key(bearer)?;
-
-    let model_data = vec![
-        serde_json::json!({
-            "id": "gpt-4o-mini",
-            "object": "model",
-            "created": 1686935002,
-            "owned_by": "openai",
-        }),
-        serde_json::json!({
-            "id": "claude-3-haiku",
-            "object": "model",
-            "created": 1686935002,
-            "owned_by": "claude",
-        }),
-        serde_json::json!({
-            "id": "llama-3.3-70b",
-            "object": "model",
-            "created": 1686935002,
-            "owned_by": "meta-llama",
-        }),
-        serde_json::json!({
-            "id": "o4-mini",
-            "object": "model",
-            "created": 1686935002,
-            "owned_by": "openai",
-        }),
-        serde_json::json!({
-            "id": "mixtral-small-3",
-            "object": "model",
-            "created": 1686935002,
-            "owned_by": "mistral ai",
-        }),
-    ];
+    let key = state.valid_key(bearer)?;
+
+    let model_data: Vec<_> = state
+        .models
+        .enabled()
+        .filter(|model| auth::allows(key, model))
+        .map(model_object)
+        .collect();
 
     Ok(Json(serde_json::json!({
         "object": "list",
@@ -64,46 +42,351 @@ pub async fn models(
     .into_response())
 }
 
+/// Model by public id, alias or upstream id (`/v1/models/{id}`)
+pub async fn retrieve_model(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(id): Path<String>,
+) -> crate::Result<Response> {
+    let key = state.valid_key(bearer)?;
+
+    let model = state
+        .models
+        .resolve(&id)
+        .filter(|model| auth::allows(key, model))
+        .ok_or(Error::ModelNotFound(id))?;
+    Ok(Json(model_object(model)).into_response())
+}
+
+/// OpenAI model object of a registry entry
+fn model_object(model: &ModelConfig) -> serde_json::Value {
+    serde_json::json!({
+        "id": model.id,
+        "object": "model",
+        "created": model.created,
+        "owned_by": model.owned_by,
+        "context_window": model.context_window,
+    })
+}
+
 pub async fn chat_completions(
     State(state): State<AppState>,
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    headers: HeaderMap,
     WithRejection(Json(mut body), _): WithRejection<Json<ChatRequest>, Error>,
 ) -> crate::Result<Response> {
-    state.valid_key(bearer)?;
-    let mut token = None;
+    let key = state.valid_key(bearer)?;
+    // request errors found before the reply starts streaming keep their status
+    state.resolve_request(key, &mut body)?;
+    let choices = body.choice_count()?;
+    let tools = body.inject_tools();
+    body.inject_format();
+    let conversation = conversation_id(&headers, body.user.as_deref(), key);
+    let format = body.json_format();
+    let stream = body.stream.unwrap_or_default();
+    let model = body.model.clone();
+    let keep_alive = state.sse_keep_alive;
+    let start = move |opened| async move {
+        let (resp, other_choices, on_complete) =
+            forward_choices(&state, &mut body, conversation, format.as_ref()).await?;
+        Ok(ChatProcess::builder()
+            .resp(resp)
+            .other_choices(other_choices)
+            .stream(body.stream)
+            .model(body.model.clone())
+            .on_complete(on_complete)
+            .prompt_tokens(tokenizer::count_messages(&body.model, &body.messages))
+            .include_usage(body.include_usage())
+            .max_tokens(body.output_limit())
+            .stop(body.stop_sequences())
+            .tools(tools)
+            .opened(opened)
+            .keep_alive(keep_alive)
+            .build())
+    };
+    if stream {
+        // show activity while the token and upstream requests are in flight
+        return Ok(process::stream_opened(
+            CompletionKind::Chat,
+            model,
+            choices,
+            keep_alive,
+            |opened| start(Some(opened)),
+        ));
+    }
+    start(None).await?.into_response().await
+}
+
+pub async fn completions(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    headers: HeaderMap,
+    WithRejection(Json(req), _): WithRejection<Json<CompletionRequest>, Error>,
+) -> crate::Result<Response> {
+    let key = state.valid_key(bearer)?;
+    let mut body = ChatRequest::try_from(req)?;
+    // request errors found before the reply starts streaming keep their status
+    state.resolve_request(key, &mut body)?;
+    let choices = body.choice_count()?;
+    let conversation = conversation_id(&headers, body.user.as_deref(), key);
+    let stream = body.stream.unwrap_or_default();
+    let model = body.model.clone();
+    let keep_alive = state.sse_keep_alive;
+    let start = move |opened| async move {
+        let (resp, other_choices, on_complete) =
+            forward_choices(&state, &mut body, conversation, None).await?;
+        Ok(ChatProcess::builder()
+            .resp(resp)
+            .other_choices(other_choices)
+            .stream(body.stream)
+            .model(body.model.clone())
+            .on_complete(on_complete)
+            .kind(CompletionKind::Text)
+            .prompt_tokens(tokenizer::count_messages(&body.model, &body.messages))
+            .include_usage(body.include_usage())
+            .max_tokens(body.output_limit())
+            .stop(body.stop_sequences())
+            .opened(opened)
+            .keep_alive(keep_alive)
+            .build())
+    };
+    if stream {
+        // show activity while the token and upstream requests are in flight
+        return Ok(process::stream_opened(
+            CompletionKind::Text,
+            model,
+            choices,
+            keep_alive,
+            |opened| start(Some(opened)),
+        ));
+    }
+    start(None).await?.into_response().await
+}
+
+/// Forward the first choice of a request, continuing the session of
+/// `conversation`, alongside the further choices of `n`, each started as a
+/// new upstream conversation.
+async fn forward_choices(
+    state: &AppState,
+    body: &mut ChatRequest,
+    conversation: Option<Conversation>,
+    format: Option<&ResponseFormat>,
+) -> Result<(
+    reqwest::Response,
+    Vec<reqwest::Response>,
+    Option<CompletionHook>,
+)> {
+    let mut others = vec![body.clone(); body.choice_count()? - 1];
+    let first = forward_choice(state, body, conversation, format);
+    let others = futures_util::future::try_join_all(others.iter_mut().map(|other| async {
+        let (resp, _) = forward_choice(state, other, None, format).await?;
+        Ok::<_, Error>(resp)
+    }));
+    let ((resp, on_complete), others) = tokio::try_join!(first, others)?;
+    Ok((resp, others, on_complete))
+}
+
+async fn forward_choice(
+    state: &AppState,
+    body: &mut ChatRequest,
+    conversation: Option<Conversation>,
+    format: Option<&ResponseFormat>,
+) -> Result<(reqwest::Response, Option<CompletionHook>)> {
+    match format {
+        Some(format) => forward_json(state, body, conversation, format).await,
+        None => forward(state, body, conversation).await,
+    }
+}
+
+/// Forward a JSON mode request, retrying with the validation error until
+/// the reply matches `format`. The returned response replays the extracted JSON.
+async fn forward_json(
+    state: &AppState,
+    body: &mut ChatRequest,
+    conversation: Option<Conversation>,
+    format: &ResponseFormat,
+) -> Result<(reqwest::Response, Option<CompletionHook>)> {
+    let original = body.clone();
+    let (mut resp, mut on_complete) = forward(state, body, conversation).await?;
+    let mut attempts = 1;
+    loop {
+        let reply = UpstreamReply::read(resp).await?;
+        let message = match format.check(&reply.text) {
+            Ok(json) => return Ok((reply.replay(&json), on_complete)),
+            Err(message) if attempts > state.json_retries => {
+                return Err(Error::ResponseFormat { attempts, message });
+            }
+            Err(message) => message,
+        };
+        tracing::debug!("JSON reply attempt {attempts} rejected: {message}");
+
+        // retries run in a new upstream conversation, not recorded in the session
+        attempts += 1;
+        *body = original.clone();
+        body.messages.extend([
+            Message::builder()
+                .role(Role::Assistant)
+                .content(Content::Text(reply.text))
+                .build(),
+            format::correction(&message),
+        ]);
+        (resp, _) = forward(state, body, None).await?;
+        on_complete = None;
+    }
+}
+
+/// Send a chat request upstream, continuing the upstream session of
+/// `conversation` when there is one.
+///
+/// The returned hook records the reply into the session once it completes.
+pub async fn forward(
+    state: &AppState,
+    body: &mut ChatRequest,
+    conversation: Option<Conversation>,
+) -> Result<(reqwest::Response, Option<CompletionHook>)> {
+    body.resolve_model(&state.models, state.default_model.as_deref())?;
+    let Some(Conversation { id, explicit }) = conversation else {
+        let (_, resp) = forward_new(state, body).await?;
+        return Ok((resp, None));
+    };
+
+    body.native_messages();
+    let mut history = body.messages.clone();
+    let mut continued = None;
+    if let Some(session) = state.sessions.get(&id) {
+        match session.new_turns(&body.messages, explicit) {
+            Some(turns) => {
+                history = [session.history, turns.clone()].concat();
+                body.messages = [session.upstream, turns].concat();
+                body.native_messages();
+                match send_request(&state.client, &state.upstream, session.hash, body).await {
+                    Ok(sent) => continued = Some(sent),
+                    Err(
+                        err @ (Error::ContentRejected { .. }
+                        | Error::UpstreamRateLimited { .. }
+                        | Error::UpstreamUnavailable { .. }),
+                    ) => {
+                        tracing::info!("session {id} rejected ({err}), restarting");
+                        state.sessions.remove(&id);
+                        body.messages = history.clone();
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            None => {
+                tracing::debug!("session {id} history diverged, restarting");
+                state.sessions.remove(&id);
+            }
+        }
+    }
+
+    let (hash, resp) = match continued {
+        Some(sent) => sent,
+        None => forward_new(state, body).await?,
+    };
+
+    let mut upstream = body.messages.clone();
+    let sessions = state.sessions.clone();
+    let on_complete: CompletionHook = Box::new(move |reply| {
+        let reply = Message::builder()
+            .role(Role::Assistant)
+            .content(Content::Text(reply))
+            .build();
+        history.push(reply.clone());
+        upstream.push(reply);
+        sessions.insert(
+            id,
+            Session {
+                hash,
+                history,
+                upstream,
+            },
+        );
+        tracing::debug!("active sessions: {}", sessions.len());
+    });
+    Ok((resp, Some(on_complete)))
+}
+
+/// Start a new upstream conversation, returning the next conversation hash
+/// with the upstream event stream
+async fn forward_new(
+    state: &AppState,
+    body: &mut ChatRequest,
+) -> Result<(String, reqwest::Response)> {
+    let token = load_token_with_retry(state).await?;
+    match body.message_mode.unwrap_or(state.message_mode) {
+        MessageMode::Compress => {
+            body.compress_messages();
+            send_request(&state.client, &state.upstream, token, body).await
+        }
+        MessageMode::Native => {
+            body.native_messages();
+            match send_request(&state.client, &state.upstream, token, body).await {
+                Err(err @ Error::ContentRejected { .. }) => {
+                    tracing::info!("upstream rejected history ({err}), compressing");
+                    body.compress_messages();
+                    let token = load_token_with_retry(state).await?;
+                    send_request(&state.client, &state.upstream, token, body).await
+                }
+                result => result,
+            }
+        }
+    }
+}
+
+async fn load_token_with_retry(state: &AppState) -> Result<String> {
+    let mut last = None;
     for _ in 0..5 {
-        match load_token(&state.client).await {
-            Ok(new_token) => {
-                token = Some(new_token);
-                break;
+        match load_token(&state.client, &state.upstream).await {
+            Ok(token) => return Ok(token),
+            // waiting out a rate limit is up to the client, and a challenge
+            // the hash can't be computed for fails the same way every time
+            Err(err @ (Error::UpstreamRateLimited { .. } | Error::HashError(_))) => {
+                return Err(err);
             }
             Err(err) => {
                 tracing::info!("retry load token: {:?}", err);
+                last = Some(err);
                 tokio::time::sleep(std::time::Duration::from_secs(1)).await;
             }
         }
     }
-    let token = token.ok_or_else(|| Error::BadRequest("cannot get token".to_string()))?;
-    body.compress_messages();
-    let (_, response) = send_request(&state.client, token, &body).await?;
-    Ok(response)
+    Err(match last {
+        Some(err @ Error::UpstreamTimeout) => err,
+        last => Error::UpstreamUnavailable {
+            message: format!(
+                "cannot get token: {}",
+                last.map(|err| err.to_string()).unwrap_or_default()
+            ),
+            retry_after: None,
+        },
+    })
 }
 
 async fn send_request(
     client: &Client,
+    upstream: &Upstream,
     hash: String,
     body: &ChatRequest,
-) -> Result<(String, Response)> {
+) -> Result<(String, reqwest::Response)> {
     // dbg!(&hash);
     let resp = client
-        .post("https://duckduckgo.com/duckchat/v1/chat")
+        .post(upstream.chat_url())
         .header(header::ACCEPT, "text/event-stream")
-        .header(header::ORIGIN, ORIGIN_API)
-        .header(header::REFERER, ORIGIN_API)
+        .header(header::ORIGIN, &upstream.origin)
+        .header(header::REFERER, &upstream.referer)
         .header("x-vqd-hash-1", hash)
         .json(&body)
         .send()
-        .await?;
+        .await
+        .map_err(Error::transport)?;
+
+    let status = resp.status();
+    if !status.is_success() {
+        let headers = resp.headers().clone();
+        let message = resp.text().await?;
+        return Err(Error::upstream(status, &headers, message));
+    }
 
     let hash = resp
         .headers()
@@ -112,25 +395,24 @@ async fn send_request(
         .ok_or_else(|| MissingHeader)?
         .to_owned();
 
-    let response = ChatProcess::builder()
-        .resp(resp)
-        .stream(body.stream)
-        .model(body.model.clone())
-        .build()
-        .into_response()
-        .await?;
-
-    Ok((hash, response))
+    Ok((hash, resp))
 }
 
-async fn load_token(client: &Client) -> Result<String> {
+async fn load_token(client: &Client, upstream: &Upstream) -> Result<String> {
     let resp = client
-        .get("https://duckduckgo.com/duckchat/v1/status")
-        .header(header::REFERER, ORIGIN_API)
+        .get(upstream.status_url())
+        .header(header::REFERER, &upstream.referer)
         .header("x-vqd-accept", "1")
         .send()
-        .await?
-        .error_for_status()?;
+        .await
+        .map_err(Error::transport)?;
+
+    let status = resp.status();
+    if !status.is_success() {
+        let headers = resp.headers().clone();
+        let message = resp.text().await?;
+        return Err(Error::upstream(status, &headers, message));
+    }
 
     let hash = resp
         .headers()
@@ -143,3 +425,1050 @@ async fn load_token(client: &Client) -> Result<String> {
 
     Ok(request_hash)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{ApiKey, ModelConfig};
+    use crate::mock::test_util::{post_json, spawn_proxy, spawn_proxy_with, sse_payloads};
+    use crate::mock::{self, Frame, Scenario, Script};
+    use crate::model::{MessageMode, UnsupportedContent};
+    use crate::session::CONVERSATION_HEADER;
+    use serde_json::{Value, json};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    async fn post_chat(addr: SocketAddr, body: Value) -> reqwest::Response {
+        post_json(addr, "/v1/chat/completions", body).await
+    }
+
+    #[tokio::test]
+    async fn chat_completions_end_to_end() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_chat(
+            addr,
+            json!({
+                "model": "gpt-4o-mini",
+                "messages": [{"role": "user", "content": "Hi"}],
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "Hello, world!");
+    }
+
+    #[tokio::test]
+    async fn chat_completions_stream_end_to_end() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_chat(
+            addr,
+            json!({
+                "model": "gpt-4o-mini",
+                "stream": true,
+                "messages": [{"role": "user", "content": "Hi"}],
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let body = resp.text().await.unwrap();
+        assert_eq!(mock::test_util::sse_payloads(&body).last(), Some(&"[DONE]"));
+    }
+
+    #[tokio::test]
+    async fn chat_completions_rejects_invalid_key() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = reqwest::Client::new()
+            .post(format!("http://{addr}/v1/chat/completions"))
+            .bearer_auth("sk-wrong")
+            .json(&json!({"model": "gpt-4o-mini", "messages": []}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn chat_completions_upstream_error() {
+        let (addr, _) = spawn_proxy(Scenario::Error.into()).await;
+        let resp = post_chat(
+            addr,
+            json!({
+                "model": "gpt-4o-mini",
+                "messages": [{"role": "user", "content": "Hi"}],
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 503);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["type"], "server_error");
+    }
+
+    #[tokio::test]
+    async fn malformed_challenge_is_an_error_response() {
+        let script = Script::from(Scenario::Normal).challenge("bm90IGEgY2hhbGxlbmdl");
+        let (addr, mock) = spawn_proxy(script).await;
+        let started = std::time::Instant::now();
+        let resp = post_chat(
+            addr,
+            json!({
+                "model": "gpt-4o-mini",
+                "messages": [{"role": "user", "content": "Hi"}],
+            }),
+        )
+        .await;
+        // not retried
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(resp.status(), 502);
+        let body: Value = resp.json().await.unwrap();
+        assert!(
+            body["message"]
+                .as_str()
+                .unwrap()
+                .contains("string array not found")
+        );
+        assert!(mock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn client_disconnect_cancels_upstream() {
+        /// Log output shared with the subscriber
+        #[derive(Clone, Default)]
+        struct Logs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Logs {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        for stream in [false, true] {
+            // the proxy runs on this test's single thread, under this subscriber
+            let logs = Logs::default();
+            let writer = logs.clone();
+            let _subscriber = tracing::subscriber::set_default(
+                tracing_subscriber::fmt()
+                    .with_writer(move || writer.clone())
+                    .with_ansi(false)
+                    .finish(),
+            );
+            let closed = Arc::new(AtomicBool::new(false));
+            let script = Script {
+                frames: vec![
+                    Frame::Message("Hello".to_owned()),
+                    Frame::Stall(closed.clone()),
+                ],
+                ..Scenario::Normal.into()
+            };
+            let (addr, _) = spawn_proxy(script).await;
+            let request = reqwest::Client::new()
+                .post(format!("http://{addr}/v1/chat/completions"))
+                .bearer_auth("sk-test")
+                .json(&json!({
+                    "model": "gpt-4o-mini",
+                    "stream": stream,
+                    "messages": [{"role": "user", "content": "Hi"}],
+                }))
+                .send();
+            let _ = tokio::time::timeout(Duration::from_millis(300), async {
+                request.await.unwrap().text().await
+            })
+            .await;
+
+            for _ in 0..50 {
+                if closed.load(Ordering::Relaxed) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            assert!(closed.load(Ordering::Relaxed), "stream: {stream}");
+
+            // the upstream's first frame was read before the client went away
+            let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+            let read_bytes: usize = logs
+                .split("read_bytes=")
+                .nth(1)
+                .and_then(|rest| rest.split(|c: char| !c.is_ascii_digit()).next())
+                .and_then(|bytes| bytes.parse().ok())
+                .unwrap_or_else(|| panic!("no read_bytes in {logs:?}"));
+            assert!(read_bytes > 0, "stream: {stream}");
+            // logged in the request span even after the handler returned
+            let line = logs
+                .lines()
+                .find(|line| line.contains("read_bytes="))
+                .unwrap();
+            assert!(line.contains("request{") && line.contains("key="), "{line}");
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_stream_sends_role_chunk_and_heartbeats() {
+        let script = Script {
+            frames: vec![Frame::Stall(Arc::new(AtomicBool::new(false)))],
+            ..Scenario::Normal.into()
+        };
+        let (addr, _) = spawn_proxy_with(script, |config| config.sse_keep_alive = 1).await;
+        let mut resp = post_json(
+            addr,
+            "/v1/chat/completions",
+            json!({
+                "model": "gpt-4o-mini",
+                "stream": true,
+                "messages": [{"role": "user", "content": "Hi"}],
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+
+        let mut text = String::new();
+        let heartbeat = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(chunk) = resp.chunk().await.unwrap() {
+                text.push_str(&String::from_utf8_lossy(&chunk));
+                if text.lines().any(|line| line.starts_with(':')) {
+                    return;
+                }
+            }
+        })
+        .await;
+        assert!(heartbeat.is_ok(), "no heartbeat in {text:?}");
+        let opening: Value = serde_json::from_str(sse_payloads(&text)[0]).unwrap();
+        assert_eq!(opening["object"], "chat.completion.chunk");
+        assert_eq!(opening["choices"][0]["delta"]["role"], "assistant");
+        assert_eq!(opening["choices"][0]["delta"]["content"], "");
+    }
+
+    #[tokio::test]
+    async fn stalled_completion_sends_empty_chunk_and_heartbeats() {
+        let script = Script {
+            frames: vec![Frame::Stall(Arc::new(AtomicBool::new(false)))],
+            ..Scenario::Normal.into()
+        };
+        let (addr, _) = spawn_proxy_with(script, |config| config.sse_keep_alive = 1).await;
+        let mut resp = post_json(
+            addr,
+            "/v1/completions",
+            json!({"model": "gpt-4o-mini", "stream": true, "prompt": "Hi"}),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+
+        let mut text = String::new();
+        let heartbeat = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(chunk) = resp.chunk().await.unwrap() {
+                text.push_str(&String::from_utf8_lossy(&chunk));
+                if text.lines().any(|line| line.starts_with(':')) {
+                    return;
+                }
+            }
+        })
+        .await;
+        assert!(heartbeat.is_ok(), "no heartbeat in {text:?}");
+        let opening: Value = serde_json::from_str(sse_payloads(&text)[0]).unwrap();
+        assert_eq!(opening["object"], "text_completion");
+        assert!(opening["id"].as_str().unwrap().starts_with("cmpl"));
+        assert_eq!(opening["choices"][0]["text"], "");
+    }
+
+    #[tokio::test]
+    async fn upstream_rate_limit_is_passed_on() {
+        let (addr, _) = spawn_proxy(Scenario::RateLimited.into()).await;
+        let resp = post_chat(
+            addr,
+            json!({
+                "model": "gpt-4o-mini",
+                "messages": [{"role": "user", "content": "Hi"}],
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 429);
+        assert_eq!(resp.headers()["retry-after"], "1");
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["code"], "rate_limit_exceeded");
+    }
+
+    fn conversation() -> Value {
+        json!([
+            {"role": "system", "content": "Be brief"},
+            {"role": "user", "content": "Hi"},
+            {"role": "assistant", "content": "Hello"},
+            {"role": "user", "content": "Bye"},
+        ])
+    }
+
+    #[tokio::test]
+    async fn compress_mode_flattens_history() {
+        let (addr, mock) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_chat(
+            addr,
+            json!({"model": "gpt-4o-mini", "messages": conversation()}),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let requests = mock.requests();
+        assert_eq!(requests[0].body["messages"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn native_mode_keeps_history() {
+        let (addr, mock) = spawn_proxy_with(Scenario::Normal.into(), |config| {
+            config.message_mode = MessageMode::Native
+        })
+        .await;
+        let resp = post_chat(
+            addr,
+            json!({"model": "gpt-4o-mini", "messages": conversation()}),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(
+            mock.requests()[0].body["messages"],
+            json!([
+                {"role": "user", "content": "Be brief\n\nHi"},
+                {"role": "assistant", "content": "Hello"},
+                {"role": "user", "content": "Bye"},
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn native_mode_per_request_override() {
+        let (addr, mock) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_chat(
+            addr,
+            json!({
+                "model": "gpt-4o-mini",
+                "message_mode": "native",
+                "messages": conversation(),
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(
+            mock.requests()[0].body["messages"]
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn native_mode_falls_back_to_compress() {
+        let (addr, mock) = spawn_proxy_with(Scenario::RejectHistory.into(), |config| {
+            config.message_mode = MessageMode::Native
+        })
+        .await;
+        let resp = post_chat(
+            addr,
+            json!({"model": "gpt-4o-mini", "messages": conversation()}),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].body["messages"].as_array().unwrap().len(), 3);
+        assert_eq!(requests[1].body["messages"].as_array().unwrap().len(), 1);
+    }
+
+    async fn post_conversation(addr: SocketAddr, id: &str, messages: Value) -> Value {
+        let resp = reqwest::Client::new()
+            .post(format!("http://{addr}/v1/chat/completions"))
+            .bearer_auth("sk-test")
+            .header(CONVERSATION_HEADER, id)
+            .json(&json!({"model": "gpt-4o-mini", "messages": messages}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        resp.json().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn session_continues_upstream_conversation() {
+        let (addr, mock) = spawn_proxy(Scenario::Normal.into()).await;
+        post_conversation(addr, "conv-1", json!([{"role": "user", "content": "Hi"}])).await;
+        post_conversation(addr, "conv-1", json!([{"role": "user", "content": "Bye"}])).await;
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].hash, "mock-next-1");
+        assert_eq!(
+            requests[1].body["messages"],
+            json!([
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": "Hello, world!"},
+                {"role": "user", "content": "Bye"},
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn session_accepts_resent_history() {
+        let (addr, mock) = spawn_proxy(Scenario::Normal.into()).await;
+        post_conversation(addr, "conv-1", json!([{"role": "user", "content": "Hi"}])).await;
+        post_conversation(
+            addr,
+            "conv-1",
+            json!([
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": "Hello, world!"},
+                {"role": "user", "content": "Bye"},
+            ]),
+        )
+        .await;
+
+        let requests = mock.requests();
+        assert_eq!(requests[1].hash, "mock-next-1");
+        assert_eq!(requests[1].body["messages"].as_array().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn sessions_are_keyed_by_conversation() {
+        let (addr, mock) = spawn_proxy(Scenario::Normal.into()).await;
+        post_conversation(addr, "conv-1", json!([{"role": "user", "content": "Hi"}])).await;
+        post_conversation(addr, "conv-2", json!([{"role": "user", "content": "Bye"}])).await;
+
+        let requests = mock.requests();
+        assert_ne!(requests[1].hash, "mock-next-1");
+        assert_eq!(requests[1].body["messages"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn user_sessions_need_resent_history() {
+        let (addr, mock) = spawn_proxy(Scenario::Normal.into()).await;
+        let chat = |messages: Value| {
+            post_chat(
+                addr,
+                json!({"model": "gpt-4o-mini", "user": "alice", "messages": messages}),
+            )
+        };
+        chat(json!([{"role": "user", "content": "Hi"}])).await;
+        // a fresh chat of the same user starts a new upstream conversation
+        chat(json!([{"role": "user", "content": "Bye"}])).await;
+        chat(json!([
+            {"role": "user", "content": "Bye"},
+            {"role": "assistant", "content": "Hello, world!"},
+            {"role": "user", "content": "Again"},
+        ]))
+        .await;
+
+        let requests = mock.requests();
+        assert_ne!(requests[1].hash, "mock-next-1");
+        assert_eq!(requests[1].body["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(requests[2].hash, "mock-next-2");
+    }
+
+    #[tokio::test]
+    async fn completions_single_response() {
+        let (addr, mock) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_json(
+            addr,
+            "/v1/completions",
+            json!({"model": "gpt-4o-mini", "prompt": ["Say hi"]}),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["object"], "text_completion");
+        assert_eq!(body["choices"][0]["text"], "Hello, world!");
+        assert!(body["choices"][0].get("message").is_none());
+        assert_eq!(
+            mock.requests()[0].body["messages"],
+            json!([{"role": "user", "content": "Say hi"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn completions_stream_response() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_json(
+            addr,
+            "/v1/completions",
+            json!({"model": "gpt-4o-mini", "prompt": "Say hi", "stream": true}),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let body = resp.text().await.unwrap();
+        let payloads = mock::test_util::sse_payloads(&body);
+        assert_eq!(payloads.last(), Some(&"[DONE]"));
+        let chunks: Vec<Value> = payloads[..payloads.len() - 1]
+            .iter()
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        assert!(chunks.iter().all(|c| c["object"] == "text_completion"));
+        let text: String = chunks
+            .iter()
+            .map(|c| c["choices"][0]["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(text, "Hello, world!");
+    }
+
+    #[tokio::test]
+    async fn stream_options_include_usage() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        for (path, body, object) in [
+            (
+                "/v1/chat/completions",
+                json!({"messages": [{"role": "user", "content": "Say hi"}]}),
+                "chat.completion.chunk",
+            ),
+            (
+                "/v1/completions",
+                json!({"prompt": "Say hi"}),
+                "text_completion",
+            ),
+        ] {
+            let mut body = body;
+            body["model"] = json!("gpt-4o-mini");
+            body["stream"] = json!(true);
+            body["stream_options"] = json!({"include_usage": true});
+            let text = post_json(addr, path, body).await.text().await.unwrap();
+            let payloads = mock::test_util::sse_payloads(&text);
+            assert_eq!(payloads.last(), Some(&"[DONE]"));
+            let chunks: Vec<Value> = payloads[..payloads.len() - 1]
+                .iter()
+                .map(|data| serde_json::from_str(data).unwrap())
+                .collect();
+
+            let (last, rest) = chunks.split_last().unwrap();
+            assert_eq!(last["object"], object);
+            assert_eq!(last["choices"], json!([]));
+            let usage = &last["usage"];
+            assert!(usage["prompt_tokens"].as_u64().unwrap() > 0);
+            assert_eq!(usage["completion_tokens"], 4);
+            assert_eq!(
+                usage["total_tokens"].as_u64(),
+                Some(usage["prompt_tokens"].as_u64().unwrap() + 4)
+            );
+            assert!(rest.iter().all(|chunk| chunk["usage"].is_null()));
+            assert!(rest.iter().all(|chunk| chunk["id"] == last["id"]));
+        }
+
+        // without the option no usage chunk is sent
+        let text = post_json(
+            addr,
+            "/v1/chat/completions",
+            json!({
+                "model": "gpt-4o-mini",
+                "stream": true,
+                "messages": [{"role": "user", "content": "Say hi"}],
+            }),
+        )
+        .await
+        .text()
+        .await
+        .unwrap();
+        let payloads = mock::test_util::sse_payloads(&text);
+        let last: Value = serde_json::from_str(payloads[payloads.len() - 2]).unwrap();
+        assert_eq!(last["choices"].as_array().unwrap().len(), 1);
+        assert!(last["usage"].is_null());
+    }
+
+    #[tokio::test]
+    async fn max_completion_tokens_supersedes_max_tokens() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_json(
+            addr,
+            "/v1/chat/completions",
+            json!({
+                "model": "gpt-4o-mini",
+                "max_tokens": 100,
+                "max_completion_tokens": 2,
+                "messages": [{"role": "user", "content": "Say hi"}],
+            }),
+        )
+        .await;
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "Hello,");
+        assert_eq!(body["choices"][0]["finish_reason"], "length");
+
+        let resp = post_json(
+            addr,
+            "/v1/completions",
+            json!({"model": "gpt-4o-mini", "prompt": "Say hi", "max_tokens": 1}),
+        )
+        .await;
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["choices"][0]["text"], "Hello");
+        assert_eq!(body["choices"][0]["finish_reason"], "length");
+    }
+
+    #[tokio::test]
+    async fn stop_accepts_string_or_list() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_json(
+            addr,
+            "/v1/chat/completions",
+            json!({
+                "model": "gpt-4o-mini",
+                "stop": "world",
+                "messages": [{"role": "user", "content": "Say hi"}],
+            }),
+        )
+        .await;
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "Hello, ");
+
+        let resp = post_json(
+            addr,
+            "/v1/completions",
+            json!({"model": "gpt-4o-mini", "prompt": "Say hi", "stop": ["", "!", "xyz"]}),
+        )
+        .await;
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["choices"][0]["text"], "Hello, world");
+        assert_eq!(body["choices"][0]["finish_reason"], "stop");
+    }
+
+    #[tokio::test]
+    async fn tool_calls_round_trip() {
+        let script = Script::messages([
+            r#"{"tool_calls": [{"name": "get_weather", "arguments": {"city": "Paris"}}]}"#,
+        ]);
+        let (addr, mock) = spawn_proxy(script).await;
+        let tools = json!([{
+            "type": "function",
+            "function": {
+                "name": "get_weather",
+                "parameters": {"type": "object", "properties": {"city": {"type": "string"}}},
+            },
+        }]);
+        let mut messages = json!([{"role": "user", "content": "Weather in Paris?"}]);
+        let resp = post_json(
+            addr,
+            "/v1/chat/completions",
+            json!({"model": "gpt-4o-mini", "tools": tools, "messages": messages}),
+        )
+        .await;
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["choices"][0]["finish_reason"], "tool_calls");
+        let call = body["choices"][0]["message"]["tool_calls"][0].clone();
+        assert_eq!(call["function"]["name"], "get_weather");
+        // the tool prompt leads the compressed history
+        let sent = mock.requests()[0].body["messages"][0]["content"].clone();
+        assert!(sent.as_str().unwrap().contains("- get_weather"));
+
+        messages.as_array_mut().unwrap().extend([
+            body["choices"][0]["message"].clone(),
+            json!({"role": "tool", "tool_call_id": call["id"], "content": "Sunny, 22C"}),
+        ]);
+        let resp = post_json(
+            addr,
+            "/v1/chat/completions",
+            json!({"model": "gpt-4o-mini", "tools": tools, "messages": messages}),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let sent = mock.requests()[1].body["messages"][0]["content"].clone();
+        let sent = sent.as_str().unwrap();
+        assert!(sent.contains(&format!(
+            "Tool result for call {}:\nSunny, 22C",
+            call["id"].as_str().unwrap()
+        )));
+        assert!(sent.contains(r#""name":"get_weather""#));
+    }
+
+    #[tokio::test]
+    async fn unsupported_content_is_rejected_by_name() {
+        let (addr, _) = spawn_proxy_with(Scenario::Normal.into(), |config| {
+            config.unsupported_content = UnsupportedContent::Reject
+        })
+        .await;
+        let resp = post_chat(
+            addr,
+            json!({
+                "model": "gpt-4o-mini",
+                "messages": [{"role": "user", "content": [
+                    {"type": "text", "text": "Describe"},
+                    {"type": "image_url", "image_url": {"url": "https://example.com/a.png"}},
+                ]}],
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 400);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["type"], "invalid_request_error");
+        assert_eq!(body["param"], "messages[0].content[1].type");
+    }
+
+    #[tokio::test]
+    async fn n_fans_out_into_choices() {
+        let (addr, mock) = spawn_proxy(Scenario::Normal.into()).await;
+        let request = |stream| {
+            json!({
+                "model": "gpt-4o-mini",
+                "n": 3,
+                "stream": stream,
+                "messages": [{"role": "user", "content": "Hi"}],
+            })
+        };
+
+        let resp = post_chat(addr, request(false)).await;
+        assert_eq!(resp.status(), 200);
+        let body: Value = resp.json().await.unwrap();
+        let choices = body["choices"].as_array().unwrap();
+        assert_eq!(choices.len(), 3);
+        for (index, choice) in choices.iter().enumerate() {
+            assert_eq!(choice["index"], index);
+            assert_eq!(choice["message"]["content"], "Hello, world!");
+        }
+        assert_eq!(body["usage"]["completion_tokens"], 12);
+        assert_eq!(mock.requests().len(), 3);
+
+        let resp = post_chat(addr, request(true)).await;
+        let body = resp.text().await.unwrap();
+        let payloads = mock::test_util::sse_payloads(&body);
+        assert_eq!(payloads.iter().filter(|data| **data == "[DONE]").count(), 1);
+        assert_eq!(payloads.last(), Some(&"[DONE]"));
+        let mut contents = vec![String::new(); 3];
+        for data in &payloads[..payloads.len() - 1] {
+            let chunk: Value = serde_json::from_str(data).unwrap();
+            let choice = &chunk["choices"][0];
+            let index = choice["index"].as_u64().unwrap() as usize;
+            contents[index].push_str(choice["delta"]["content"].as_str().unwrap_or_default());
+        }
+        assert_eq!(contents, vec!["Hello, world!"; 3]);
+        assert_eq!(mock.requests().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn n_out_of_range_is_rejected() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_chat(
+            addr,
+            json!({
+                "model": "gpt-4o-mini",
+                "n": 0,
+                "messages": [{"role": "user", "content": "Hi"}],
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 400);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["param"], "n");
+    }
+
+    #[tokio::test]
+    async fn unknown_model_is_not_found() {
+        let (addr, mock) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_chat(
+            addr,
+            json!({
+                "model": "gpt-9",
+                "messages": [{"role": "user", "content": "Hi"}],
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 404);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["type"], "invalid_request_error");
+        assert_eq!(body["code"], "model_not_found");
+        assert_eq!(body["param"], "model");
+        assert!(mock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_model_falls_back_to_default() {
+        let (addr, mock) = spawn_proxy_with(Scenario::Normal.into(), |config| {
+            config.default_model = Some("claude-3-haiku".to_owned())
+        })
+        .await;
+        let resp = post_chat(
+            addr,
+            json!({
+                "model": "gpt-9",
+                "messages": [{"role": "user", "content": "Hi"}],
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(mock.requests()[0].body["model"], "claude-3-haiku-20240307");
+    }
+
+    #[tokio::test]
+    async fn retrieve_model_resolves_aliases() {
+        let (addr, _) = spawn_proxy(Scenario::Normal.into()).await;
+        let get = |id: &'static str| async move {
+            reqwest::Client::new()
+                .get(format!("http://{addr}/v1/models/{id}"))
+                .bearer_auth("sk-test")
+                .send()
+                .await
+                .unwrap()
+        };
+        for id in ["llama-3.3-70b", "meta-llama/Llama-3.3-70B-Instruct-Turbo"] {
+            let resp = get(id).await;
+            assert_eq!(resp.status(), 200);
+            let body: Value = resp.json().await.unwrap();
+            assert_eq!(body["id"], "llama-3.3-70b");
+            assert_eq!(body["object"], "model");
+            assert_eq!(body["owned_by"], "meta-llama");
+        }
+
+        let resp = get("gpt-9").await;
+        assert_eq!(resp.status(), 404);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["code"], "model_not_found");
+    }
+
+    #[tokio::test]
+    async fn configured_models_replace_the_defaults() {
+        let (addr, mock) = spawn_proxy_with(Scenario::Normal.into(), |config| {
+            config.models = vec![ModelConfig {
+                id: "fast".to_owned(),
+                upstream: "gpt-4o-mini".to_owned(),
+                owned_by: "openai".to_owned(),
+                created: 1,
+                context_window: 128_000,
+                enabled: true,
+                aliases: vec!["quick".to_owned()],
+            }];
+        })
+        .await;
+        let resp = reqwest::Client::new()
+            .get(format!("http://{addr}/v1/models"))
+            .bearer_auth("sk-test")
+            .send()
+            .await
+            .unwrap();
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["data"].as_array().unwrap().len(), 1);
+        assert_eq!(body["data"][0]["id"], "fast");
+
+        let chat = |model| {
+            post_chat(
+                addr,
+                json!({"model": model, "messages": [{"role": "user", "content": "Hi"}]}),
+            )
+        };
+        assert_eq!(chat("quick").await.status(), 200);
+        assert_eq!(mock.requests()[0].body["model"], "gpt-4o-mini");
+        assert_eq!(chat("claude-3-haiku").await.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn api_keys_restrict_models() {
+        let (addr, _) = spawn_proxy_with(Scenario::Normal.into(), |config| {
+            config.api_keys = vec![ApiKey {
+                name: "ci".to_owned(),
+                key: Some("sk-ci".to_owned()),
+                key_hash: None,
+                enabled: true,
+                models: vec!["gpt-4o-mini".to_owned()],
+                expires_at: None,
+            }];
+        })
+        .await;
+        let client = reqwest::Client::new();
+        let chat = |model: &'static str| {
+            client
+                .post(format!("http://{addr}/v1/chat/completions"))
+                .bearer_auth("sk-ci")
+                .json(&json!({
+                    "model": model,
+                    "messages": [{"role": "user", "content": "Hi"}],
+                }))
+                .send()
+        };
+        assert_eq!(chat("gpt-4o-mini").await.unwrap().status(), 200);
+        let resp = chat("o4-mini").await.unwrap();
+        assert_eq!(resp.status(), 404);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["code"], "model_not_found");
+
+        let resp = client
+            .get(format!("http://{addr}/v1/models"))
+            .bearer_auth("sk-ci")
+            .send()
+            .await
+            .unwrap();
+        let body: Value = resp.json().await.unwrap();
+        let ids: Vec<_> = body["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|model| model["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, ["gpt-4o-mini"]);
+
+        // the legacy key of the test config stays unrestricted
+        let resp = post_chat(
+            addr,
+            json!({
+                "model": "o4-mini",
+                "messages": [{"role": "user", "content": "Hi"}],
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn key_access_follows_the_requested_entry() {
+        let (addr, mock) = spawn_proxy_with(Scenario::Normal.into(), |config| {
+            // shares its upstream id with the built-in gpt-4o-mini entry
+            config.models.push(ModelConfig {
+                id: "fast".to_owned(),
+                upstream: "gpt-4o-mini".to_owned(),
+                owned_by: "openai".to_owned(),
+                created: 1,
+                context_window: 128_000,
+                enabled: true,
+                aliases: Vec::new(),
+            });
+            config.api_keys = vec![ApiKey {
+                name: "ci".to_owned(),
+                key: Some("sk-ci".to_owned()),
+                key_hash: None,
+                enabled: true,
+                models: vec!["fast".to_owned()],
+                expires_at: None,
+            }];
+        })
+        .await;
+        let chat = |model: &'static str| {
+            reqwest::Client::new()
+                .post(format!("http://{addr}/v1/chat/completions"))
+                .bearer_auth("sk-ci")
+                .json(&json!({
+                    "model": model,
+                    "messages": [{"role": "user", "content": "Hi"}],
+                }))
+                .send()
+        };
+        assert_eq!(chat("fast").await.unwrap().status(), 200);
+        assert_eq!(mock.requests()[0].body["model"], "gpt-4o-mini");
+        assert_eq!(chat("gpt-4o-mini").await.unwrap().status(), 404);
+    }
+
+    fn answer_format() -> Value {
+        json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": "answer",
+                "schema": {
+                    "type": "object",
+                    "properties": {"answer": {"type": "integer"}},
+                    "required": ["answer"],
+                },
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn json_schema_reply_is_retried_until_valid() {
+        let script =
+            Script::messages([r#"Sure: {"answer": "forty-two"}"#]).then(Script::messages([
+                "```json\n",
+                r#"{"answer": 42}"#,
+                "\n```",
+            ]));
+        let (addr, mock) = spawn_proxy(script).await;
+        let resp = post_json(
+            addr,
+            "/v1/chat/completions",
+            json!({
+                "model": "gpt-4o-mini",
+                "response_format": answer_format(),
+                "messages": [{"role": "user", "content": "6 x 7?"}],
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(
+            body["choices"][0]["message"]["content"],
+            r#"{"answer": 42}"#
+        );
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2);
+        let first = requests[0].body["messages"][0]["content"].as_str().unwrap();
+        assert!(first.contains("must match this JSON schema"));
+        let retry = requests[1].body["messages"][0]["content"].as_str().unwrap();
+        assert!(retry.contains("$.answer: expected integer, got string"));
+    }
+
+    #[tokio::test]
+    async fn json_reply_that_never_validates_is_an_error() {
+        let (addr, mock) =
+            spawn_proxy_with(Scenario::Normal.into(), |config| config.json_retries = 1).await;
+        let resp = post_json(
+            addr,
+            "/v1/chat/completions",
+            json!({
+                "model": "gpt-4o-mini",
+                "stream": true,
+                "response_format": {"type": "json_object"},
+                "messages": [{"role": "user", "content": "Hi"}],
+            }),
+        )
+        .await;
+        // the stream is already open, so the failure arrives as an error event
+        assert_eq!(resp.status(), 200);
+        let text = resp.text().await.unwrap();
+        let events = sse_payloads(&text);
+        let opening: Value = serde_json::from_str(events[0]).unwrap();
+        assert_eq!(opening["choices"][0]["delta"]["role"], "assistant");
+        let error: Value = serde_json::from_str(events.last().unwrap()).unwrap();
+        assert_eq!(error["error"]["param"], "response_format");
+        assert!(
+            error["error"]["message"]
+                .as_str()
+                .unwrap()
+                .contains("after 2 attempts")
+        );
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn json_object_reply_streams_extracted_json() {
+        let (addr, _) = spawn_proxy(Script::messages(["Here: ", r#"{"ok": true}"#])).await;
+        let resp = post_json(
+            addr,
+            "/v1/chat/completions",
+            json!({
+                "model": "gpt-4o-mini",
+                "stream": true,
+                "response_format": {"type": "json_object"},
+                "messages": [{"role": "user", "content": "Hi"}],
+            }),
+        )
+        .await;
+        let text = resp.text().await.unwrap();
+        let payloads = mock::test_util::sse_payloads(&text);
+        assert_eq!(payloads.last(), Some(&"[DONE]"));
+        let content: String = payloads[..payloads.len() - 1]
+            .iter()
+            .map(|data| serde_json::from_str::<Value>(data).unwrap())
+            .filter_map(|chunk| {
+                chunk["choices"][0]["delta"]["content"]
+                    .as_str()
+                    .map(ToOwned::to_owned)
+            })
+            .collect();
+        assert_eq!(content, r#"{"ok": true}"#);
+    }
+
+    #[tokio::test]
+    async fn completions_rejects_prompt_batches() {
+        let (addr, mock) = spawn_proxy(Scenario::Normal.into()).await;
+        let resp = post_json(
+            addr,
+            "/v1/completions",
+            json!({"model": "gpt-4o-mini", "prompt": ["a", "b"]}),
+        )
+        .await;
+        assert_eq!(resp.status(), 400);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["type"], "invalid_request_error");
+        assert_eq!(body["param"], "prompt");
+        assert!(mock.requests().is_empty());
+    }
+}
@@ -0,0 +1,357 @@
+//! JSON Schema validation of structured replies.
+//!
+//! Covers the subset of JSON Schema accepted by OpenAI structured outputs:
+//! types, `enum`/`const`, object properties, arrays, string and number
+//! bounds, composition and local `$ref`s. Other keywords are ignored.
+
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+
+/// Validate `instance` against `schema`, describing the first violation
+pub fn validate(schema: &Value, instance: &Value) -> Result<(), String> {
+    Validator {
+        root: schema,
+        resolving: RefCell::new(Vec::new()),
+    }
+    .check(schema, instance, "$")
+}
+
+struct Validator<'a> {
+    root: &'a Value,
+    /// `$ref`s being resolved, with the path they apply to. The same one
+    /// again at the same path is a cycle that would never end.
+    resolving: RefCell<Vec<(String, String)>>,
+}
+
+impl Validator<'_> {
+    fn check(&self, schema: &Value, instance: &Value, path: &str) -> Result<(), String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(()),
+            Value::Bool(false) => return Err(format!("{path}: no value is allowed")),
+            Value::Object(schema) => schema,
+            _ => return Ok(()),
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let target = self
+                .resolve(reference)
+                .ok_or_else(|| format!("{path}: unresolvable $ref {reference}"))?;
+            let key = (reference.to_owned(), path.to_owned());
+            if self.resolving.borrow().contains(&key) {
+                return Err(format!("{path}: $ref {reference} refers to itself"));
+            }
+            self.resolving.borrow_mut().push(key);
+            let result = self.check(target, instance, path);
+            self.resolving.borrow_mut().pop();
+            result?;
+        }
+        if let Some(types) = schema.get("type") {
+            let allowed: Vec<&str> = match types {
+                Value::String(name) => vec![name.as_str()],
+                Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            if !allowed.is_empty() && !allowed.iter().any(|name| is_type(instance, name)) {
+                return Err(format!(
+                    "{path}: expected {}, got {}",
+                    allowed.join(" or "),
+                    type_name(instance)
+                ));
+            }
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array)
+            && !values.contains(instance)
+        {
+            return Err(format!(
+                "{path}: {instance} is not one of {}",
+                Value::from(values.clone())
+            ));
+        }
+        if let Some(value) = schema.get("const")
+            && value != instance
+        {
+            return Err(format!("{path}: expected {value}"));
+        }
+
+        match instance {
+            Value::Object(object) => self.check_object(schema, object, path)?,
+            Value::Array(items) => self.check_array(schema, items, path)?,
+            Value::String(text) => check_string(schema, text, path)?,
+            Value::Number(_) => check_number(schema, instance, path)?,
+            _ => {}
+        }
+        self.check_composition(schema, instance, path)
+    }
+
+    fn check_object(
+        &self,
+        schema: &Map<String, Value>,
+        object: &Map<String, Value>,
+        path: &str,
+    ) -> Result<(), String> {
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(name) {
+                    return Err(format!("{path}: missing required property '{name}'"));
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (name, value) in object {
+            let path = format!("{path}.{name}");
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(property) => self.check(property, value, &path)?,
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        return Err(format!("{path}: unexpected property"));
+                    }
+                    Some(additional) => self.check(additional, value, &path)?,
+                    None => {}
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn check_array(
+        &self,
+        schema: &Map<String, Value>,
+        items: &[Value],
+        path: &str,
+    ) -> Result<(), String> {
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64)
+            && (items.len() as u64) < min
+        {
+            return Err(format!("{path}: expected at least {min} items"));
+        }
+        if let Some(max) = schema.get("maxItems").and_then(Value::as_u64)
+            && (items.len() as u64) > max
+        {
+            return Err(format!("{path}: expected at most {max} items"));
+        }
+        let prefix = schema
+            .get("prefixItems")
+            .and_then(Value::as_array)
+            .map_or(&[][..], Vec::as_slice);
+        for (index, item) in items.iter().enumerate() {
+            let path = format!("{path}[{index}]");
+            match (prefix.get(index), schema.get("items")) {
+                (Some(schema), _) | (None, Some(schema)) => self.check(schema, item, &path)?,
+                (None, None) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn check_composition(
+        &self,
+        schema: &Map<String, Value>,
+        instance: &Value,
+        path: &str,
+    ) -> Result<(), String> {
+        let schemas = |keyword| {
+            schema
+                .get(keyword)
+                .and_then(Value::as_array)
+                .map_or(&[][..], Vec::as_slice)
+        };
+        for schema in schemas("allOf") {
+            self.check(schema, instance, path)?;
+        }
+        let any_of = schemas("anyOf");
+        if !any_of.is_empty()
+            && !any_of
+                .iter()
+                .any(|schema| self.check(schema, instance, path).is_ok())
+        {
+            return Err(format!("{path}: does not match any allowed schema"));
+        }
+        let one_of = schemas("oneOf");
+        if !one_of.is_empty() {
+            let matches = one_of
+                .iter()
+                .filter(|schema| self.check(schema, instance, path).is_ok())
+                .count();
+            if matches != 1 {
+                return Err(format!("{path}: matches {matches} schemas instead of one"));
+            }
+        }
+        if let Some(schema) = schema.get("not")
+            && self.check(schema, instance, path).is_ok()
+        {
+            return Err(format!("{path}: matches a disallowed schema"));
+        }
+        Ok(())
+    }
+
+    /// Schema at a local `#/...` JSON pointer
+    fn resolve(&self, reference: &str) -> Option<&Value> {
+        let pointer = reference.strip_prefix('#')?;
+        self.root.pointer(pointer)
+    }
+}
+
+fn check_string(schema: &Map<String, Value>, text: &str, path: &str) -> Result<(), String> {
+    let len = text.chars().count() as u64;
+    if let Some(min) = schema.get("minLength").and_then(Value::as_u64)
+        && len < min
+    {
+        return Err(format!("{path}: shorter than {min} characters"));
+    }
+    if let Some(max) = schema.get("maxLength").and_then(Value::as_u64)
+        && len > max
+    {
+        return Err(format!("{path}: longer than {max} characters"));
+    }
+    if let Some(pattern) = schema.get("pattern").and_then(Value::as_str)
+        && let Ok(regex) = Regex::new(pattern)
+        && !regex.is_match(text)
+    {
+        return Err(format!("{path}: does not match pattern {pattern}"));
+    }
+    Ok(())
+}
+
+fn check_number(schema: &Map<String, Value>, number: &Value, path: &str) -> Result<(), String> {
+    let Some(number) = number.as_f64() else {
+        return Ok(());
+    };
+    let bound = |keyword| schema.get(keyword).and_then(Value::as_f64);
+    if let Some(min) = bound("minimum")
+        && number < min
+    {
+        return Err(format!("{path}: less than {min}"));
+    }
+    if let Some(max) = bound("maximum")
+        && number > max
+    {
+        return Err(format!("{path}: greater than {max}"));
+    }
+    if let Some(min) = bound("exclusiveMinimum")
+        && number <= min
+    {
+        return Err(format!("{path}: not greater than {min}"));
+    }
+    if let Some(max) = bound("exclusiveMaximum")
+        && number >= max
+    {
+        return Err(format!("{path}: not less than {max}"));
+    }
+    Ok(())
+}
+
+fn is_type(instance: &Value, name: &str) -> bool {
+    match name {
+        "object" => instance.is_object(),
+        "array" => instance.is_array(),
+        "string" => instance.is_string(),
+        "number" => instance.is_number(),
+        "integer" => {
+            instance.is_i64()
+                || instance.is_u64()
+                || instance.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        "boolean" => instance.is_boolean(),
+        "null" => instance.is_null(),
+        _ => true,
+    }
+}
+
+fn type_name(instance: &Value) -> &'static str {
+    match instance {
+        Value::Object(_) => "object",
+        Value::Array(_) => "array",
+        Value::String(_) => "string",
+        Value::Number(_) => "number",
+        Value::Bool(_) => "boolean",
+        Value::Null => "null",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn person() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string", "minLength": 1},
+                "age": {"type": "integer", "minimum": 0},
+                "tags": {"type": "array", "items": {"$ref": "#/$defs/tag"}, "maxItems": 2},
+            },
+            "required": ["name", "age"],
+            "additionalProperties": false,
+            "$defs": {"tag": {"enum": ["admin", "user"]}},
+        })
+    }
+
+    #[test]
+    fn valid_instance_passes() {
+        let instance = json!({"name": "Ada", "age": 36, "tags": ["admin"]});
+        assert_eq!(validate(&person(), &instance), Ok(()));
+    }
+
+    #[test]
+    fn violations_name_the_path() {
+        let cases = [
+            (json!({"name": "Ada"}), "$: missing required property 'age'"),
+            (
+                json!({"name": "Ada", "age": "36"}),
+                "$.age: expected integer, got string",
+            ),
+            (json!({"name": "Ada", "age": -1}), "$.age: less than 0"),
+            (
+                json!({"name": "Ada", "age": 1, "x": 1}),
+                "$.x: unexpected property",
+            ),
+            (
+                json!({"name": "Ada", "age": 1, "tags": ["root"]}),
+                r#"$.tags[0]: "root" is not one of ["admin","user"]"#,
+            ),
+            (
+                json!({"name": "Ada", "age": 1, "tags": ["user", "user", "user"]}),
+                "$.tags: expected at most 2 items",
+            ),
+        ];
+        for (instance, error) in cases {
+            assert_eq!(validate(&person(), &instance), Err(error.to_owned()));
+        }
+    }
+
+    #[test]
+    fn ref_cycles_are_errors() {
+        let schema = json!({"$ref": "#"});
+        assert_eq!(
+            validate(&schema, &json!(1)),
+            Err("$: $ref # refers to itself".to_owned())
+        );
+
+        let schema = json!({
+            "$ref": "#/$defs/a",
+            "$defs": {"a": {"$ref": "#/$defs/b"}, "b": {"anyOf": [{"$ref": "#/$defs/a"}]}},
+        });
+        assert!(validate(&schema, &json!(1)).is_err());
+
+        // recursion that follows the instance down is fine
+        let tree = json!({
+            "type": "object",
+            "properties": {"children": {"type": "array", "items": {"$ref": "#"}}},
+        });
+        let instance = json!({"children": [{"children": [{"children": []}]}]});
+        assert_eq!(validate(&tree, &instance), Ok(()));
+    }
+
+    #[test]
+    fn composition_keywords() {
+        let schema = json!({"anyOf": [{"type": "string"}, {"type": "null"}]});
+        assert!(validate(&schema, &json!(null)).is_ok());
+        assert!(validate(&schema, &json!(1)).is_err());
+
+        let schema = json!({"oneOf": [{"type": "integer"}, {"type": "number"}]});
+        assert!(validate(&schema, &json!(1.5)).is_ok());
+        assert!(validate(&schema, &json!(1)).is_err());
+    }
+}
@@ -1,8 +1,15 @@
+use crate::auth::{self, KeyTable};
 use crate::client::{HttpConfig, build_client};
+use crate::config::{ApiKey, Upstream};
+use crate::model::{ChatRequest, MessageMode, ModelRegistry, UnsupportedContent};
+use crate::responses::ResponseStore;
+use crate::session::SessionStore;
 use crate::{Result, config::Config, error::Error};
 use axum::{
     Json, Router,
-    http::StatusCode,
+    extract::Request,
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
     response::IntoResponse,
     routing::{get, post},
 };
@@ -14,28 +21,53 @@ use reqwest::Client;
 use serde::Serialize;
 use std::{path::PathBuf, sync::Arc, time::Duration};
 use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
-use tracing::Level;
+use tracing::{Instrument, Level};
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 use typed_builder::TypedBuilder;
 
 #[derive(Clone, TypedBuilder)]
 pub struct AppState {
     pub client: Client,
-    api_key: Arc<Option<String>>,
+    pub upstream: Arc<Upstream>,
+    pub message_mode: MessageMode,
+    pub sessions: Arc<SessionStore>,
+    pub responses: Arc<ResponseStore>,
+    /// Upstream retries when a JSON reply doesn't match its format
+    pub json_retries: u32,
+    /// Policy for content parts the upstream can't take
+    pub unsupported_content: UnsupportedContent,
+    /// Model serving requests for unknown models
+    pub default_model: Option<String>,
+    /// Interval of SSE heartbeats on streamed replies
+    pub sse_keep_alive: Option<Duration>,
+    /// Models served by the proxy
+    pub models: Arc<ModelRegistry>,
+    keys: Arc<KeyTable>,
 }
 
 impl AppState {
+    /// Key authenticating a request, `None` when requests need no key
     pub fn valid_key(
         &self,
         bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    ) -> crate::Result<Option<&ApiKey>> {
+        self.valid_api_key(bearer.as_deref().map(|b| b.token()))
+    }
+
+    pub fn valid_api_key(&self, api_key: Option<&str>) -> crate::Result<Option<&ApiKey>> {
+        self.keys.authenticate(api_key)
+    }
+
+    /// Resolve the model of `body`, which `key` must be allowed to use, and
+    /// apply the `unsupported_content` policy to its messages
+    pub fn resolve_request(
+        &self,
+        key: Option<&ApiKey>,
+        body: &mut ChatRequest,
     ) -> crate::Result<()> {
-        let api_key = bearer.as_deref().map(|b| b.token());
-        if let Some(key) = self.api_key.as_deref() {
-            if Some(key) != api_key {
-                return Err(crate::Error::InvalidApiKey);
-            }
-        }
-        Ok(())
+        let model = body.resolve_model(&self.models, self.default_model.as_deref())?;
+        auth::check_model(key, model)?;
+        body.resolve_unsupported(self.unsupported_content)
     }
 }
 
@@ -50,31 +82,7 @@ pub async fn run(path: PathBuf) -> Result<()> {
     // init boot message
     boot_message(&config);
 
-    // init global layer provider
-    let global_layer = tower::ServiceBuilder::new().layer(
-        CorsLayer::new()
-            .allow_credentials(true)
-            .allow_headers(AllowHeaders::mirror_request())
-            .allow_methods(AllowMethods::mirror_request())
-            .allow_origin(AllowOrigin::mirror_request()),
-    );
-
-    let http_config = HttpConfig::builder()
-        .timeout(config.timeout)
-        .connect_timeout(config.connect_timeout)
-        .tcp_keepalive(config.tcp_keepalive)
-        .build();
-
-    let app_state = AppState::builder()
-        .client(build_client(http_config).await)
-        .api_key(Arc::new(config.api_key))
-        .build();
-
-    let router = Router::new()
-        .route("/v1/models", get(crate::route::models))
-        .route("/v1/chat/completions", post(crate::route::chat_completions))
-        .with_state(app_state)
-        .layer(global_layer);
+    let router = router(app_state(&config).await);
 
     // http server tcp keepalive
     let tcp_keepalive = config.tcp_keepalive.map(Duration::from_secs);
@@ -113,12 +121,85 @@ pub async fn run(path: PathBuf) -> Result<()> {
     .map_err(Into::into)
 }
 
+/// Build the application state from the configuration
+pub async fn app_state(config: &Config) -> AppState {
+    let http_config = HttpConfig::builder()
+        .timeout(config.timeout)
+        .connect_timeout(config.connect_timeout)
+        .tcp_keepalive(config.tcp_keepalive)
+        .build();
+
+    AppState::builder()
+        .client(build_client(http_config).await)
+        .upstream(Arc::new(config.upstream.clone()))
+        .message_mode(config.message_mode)
+        .sessions(Arc::new(SessionStore::new(
+            Duration::from_secs(config.sessions.ttl),
+            config.sessions.capacity,
+        )))
+        .responses(Arc::new(ResponseStore::new(
+            Duration::from_secs(config.responses.ttl),
+            config.responses.capacity,
+        )))
+        .json_retries(config.json_retries)
+        .unsupported_content(config.unsupported_content)
+        .default_model(config.default_model.clone())
+        .models(Arc::new(ModelRegistry::new(config.models.clone())))
+        .sse_keep_alive(
+            (config.sse_keep_alive > 0).then(|| Duration::from_secs(config.sse_keep_alive)),
+        )
+        .keys(Arc::new(KeyTable::new(
+            config.api_key.clone(),
+            config.api_keys.clone(),
+        )))
+        .build()
+}
+
+/// Build the application router
+pub fn router(app_state: AppState) -> Router {
+    // init global layer provider
+    let global_layer = tower::ServiceBuilder::new()
+        .layer(axum::middleware::from_fn(request_span))
+        .layer(
+            CorsLayer::new()
+                .allow_credentials(true)
+                .allow_headers(AllowHeaders::mirror_request())
+                .allow_methods(AllowMethods::mirror_request())
+                .allow_origin(AllowOrigin::mirror_request()),
+        );
+
+    Router::new()
+        .route("/v1/models", get(crate::route::models))
+        .route("/v1/models/{*id}", get(crate::route::retrieve_model))
+        .route("/v1/chat/completions", post(crate::route::chat_completions))
+        .route("/v1/completions", post(crate::route::completions))
+        .route("/v1/responses", post(crate::responses::responses))
+        .route("/v1/messages", post(crate::anthropic::messages))
+        .route("/api/chat", post(crate::ollama::chat))
+        .route("/api/generate", post(crate::ollama::generate))
+        .route("/api/tags", get(crate::ollama::tags))
+        .with_state(app_state)
+        .layer(global_layer)
+}
+
+/// Run each request in a span, where authentication records the key name
+async fn request_span(req: Request, next: Next) -> axum::response::Response {
+    let span = tracing::info_span!(
+        "request",
+        method = %req.method(),
+        path = req.uri().path(),
+        key = tracing::field::Empty,
+    );
+    next.run(req).instrument(span).await
+}
+
 fn boot_message(config: &Config) {
     tracing::info!("Bind address: {}", config.bind);
+    tracing::info!("Upstream: {}", config.upstream.base_url);
 }
 
 /// Initialize the logger with a filter that ignores WARN level logs for netlink_proto
-fn init_logger(debug: bool) -> Result<()> {
+pub fn init_logger(debug: bool) -> Result<()> {
     let filter = EnvFilter::from_default_env()
         .add_directive(if debug { Level::DEBUG } else { Level::INFO }.into())
         .add_directive("netlink_proto=error".parse()?);
@@ -138,48 +219,141 @@ async fn init_config(path: PathBuf) -> Result<Config> {
     }
 }
 
-impl IntoResponse for Error {
-    fn into_response(self) -> axum::response::Response {
-        #[derive(Serialize, TypedBuilder)]
-        struct ResponseError {
-            message: String,
-            #[serde(rename = "type")]
-            type_field: &'static str,
-            #[builder(default)]
-            param: Option<String>,
-        }
+/// OpenAI error object
+#[derive(Serialize, TypedBuilder)]
+pub struct ResponseError {
+    message: String,
+    #[serde(rename = "type")]
+    type_field: &'static str,
+    #[builder(default)]
+    param: Option<String>,
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
+}
 
+impl Error {
+    /// Status, error object and upstream `Retry-After` of the error
+    pub fn parts(self) -> (StatusCode, ResponseError, Option<String>) {
         match self {
             Error::JsonExtractorRejection(json_rejection) => (
                 StatusCode::BAD_REQUEST,
-                Json(
-                    ResponseError::builder()
-                        .message(json_rejection.body_text())
-                        .type_field("invalid_request_error")
-                        .build(),
-                ),
-            )
-                .into_response(),
+                ResponseError::builder()
+                    .message(json_rejection.body_text())
+                    .type_field("invalid_request_error")
+                    .build(),
+                None,
+            ),
+            Error::InvalidRequest { message, param } => (
+                StatusCode::BAD_REQUEST,
+                ResponseError::builder()
+                    .message(message)
+                    .type_field("invalid_request_error")
+                    .param(param)
+                    .build(),
+                None,
+            ),
+            Error::ModelNotFound(_) => (
+                StatusCode::NOT_FOUND,
+                ResponseError::builder()
+                    .message(self.to_string())
+                    .type_field("invalid_request_error")
+                    .param(Some("model".to_owned()))
+                    .code(Some("model_not_found"))
+                    .build(),
+                None,
+            ),
+            Error::UpstreamRateLimited {
+                ref retry_after, ..
+            } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                ResponseError::builder()
+                    .message(self.to_string())
+                    .type_field("rate_limit_error")
+                    .code(Some("rate_limit_exceeded"))
+                    .build(),
+                retry_after.clone(),
+            ),
+            Error::UpstreamUnavailable {
+                ref retry_after, ..
+            } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                ResponseError::builder()
+                    .message(self.to_string())
+                    .type_field("server_error")
+                    .build(),
+                retry_after.clone(),
+            ),
+            Error::UpstreamTimeout => (
+                StatusCode::GATEWAY_TIMEOUT,
+                ResponseError::builder()
+                    .message(self.to_string())
+                    .type_field("timeout_error")
+                    .build(),
+                None,
+            ),
+            Error::HashError(_) => (
+                StatusCode::BAD_GATEWAY,
+                ResponseError::builder()
+                    .message(format!("cannot answer the upstream challenge: {self}"))
+                    .type_field("server_error")
+                    .build(),
+                None,
+            ),
+            Error::UpstreamInterrupted(_) => (
+                StatusCode::BAD_GATEWAY,
+                ResponseError::builder()
+                    .message(self.to_string())
+                    .type_field("server_error")
+                    .build(),
+                None,
+            ),
+            Error::ContentRejected { .. } => (
+                StatusCode::BAD_GATEWAY,
+                ResponseError::builder()
+                    .message(self.to_string())
+                    .type_field("invalid_request_error")
+                    .code(Some("content_rejected"))
+                    .build(),
+                None,
+            ),
+            Error::ResponseFormat { .. } => (
+                StatusCode::BAD_GATEWAY,
+                ResponseError::builder()
+                    .message(self.to_string())
+                    .type_field("server_error")
+                    .param(Some("response_format".to_owned()))
+                    .build(),
+                None,
+            ),
             Error::InvalidApiKey => (
                 StatusCode::UNAUTHORIZED,
-                Json(
-                    ResponseError::builder()
-                        .message(self.to_string())
-                        .type_field("invalid_request_error")
-                        .build(),
-                ),
-            )
-                .into_response(),
+                ResponseError::builder()
+                    .message(self.to_string())
+                    .type_field("invalid_request_error")
+                    .build(),
+                None,
+            ),
             _ => (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                Json(
-                    ResponseError::builder()
-                        .message(self.to_string())
-                        .type_field("server_error")
-                        .build(),
-                ),
-            )
-                .into_response(),
+                ResponseError::builder()
+                    .message(self.to_string())
+                    .type_field("server_error")
+                    .build(),
+                None,
+            ),
+        }
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> axum::response::Response {
+        let (status, error, retry_after) = self.parts();
+        let mut resp = (status, Json(error)).into_response();
+        // pass the upstream `Retry-After` on to the client
+        if let Some(value) = retry_after.and_then(|value| HeaderValue::from_str(&value).ok()) {
+            resp.headers_mut().insert(header::RETRY_AFTER, value);
         }
+        resp
     }
 }
@@ -0,0 +1,168 @@
+use crate::auth;
+use crate::config::ApiKey;
+use crate::model::{Message, Role};
+use crate::store::TtlStore;
+use axum::http::HeaderMap;
+
+/// Header carrying the client's conversation id
+pub const CONVERSATION_HEADER: &str = "x-conversation-id";
+
+pub type SessionStore = TtlStore<Session>;
+
+/// Upstream conversation continued across requests
+#[derive(Clone)]
+pub struct Session {
+    /// `x-vqd-hash-1` returned with the last upstream reply
+    pub hash: String,
+    /// Conversation as sent by the client, including the last reply
+    pub history: Vec<Message>,
+    /// Messages sent upstream, including the last reply
+    pub upstream: Vec<Message>,
+}
+
+/// Client conversation a request belongs to
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conversation {
+    /// Session store id, scoped to the key of the request
+    pub id: String,
+    /// Named by the `x-conversation-id` header rather than the `user` field
+    pub explicit: bool,
+}
+
+impl Session {
+    /// Turns of `messages` not yet part of this conversation.
+    ///
+    /// Clients may either resend the whole history or, in an `explicit`
+    /// conversation, only the new turns; `None` means the history diverges
+    /// and the session can't be continued.
+    pub fn new_turns(&self, messages: &[Message], explicit: bool) -> Option<Vec<Message>> {
+        let turns = if messages.starts_with(&self.history) {
+            &messages[self.history.len()..]
+        } else if explicit && messages.iter().all(|m| m.role != Some(Role::Assistant)) {
+            messages
+        } else {
+            return None;
+        };
+        (!turns.is_empty()).then(|| turns.to_vec())
+    }
+}
+
+/// Conversation from the `x-conversation-id` header or the OpenAI `user`
+/// field, of the requests made with `key`
+pub fn conversation_id(
+    headers: &HeaderMap,
+    user: Option<&str>,
+    key: Option<&ApiKey>,
+) -> Option<Conversation> {
+    let header = headers
+        .get(CONVERSATION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty());
+    let (id, explicit) = match header {
+        Some(id) => (id, true),
+        None => (user.filter(|id| !id.is_empty())?, false),
+    };
+    Some(Conversation {
+        id: auth::scoped_id(key, id),
+        explicit,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Content;
+
+    fn message(role: Role, text: &str) -> Message {
+        Message::builder()
+            .role(role)
+            .content(Content::Text(text.to_owned()))
+            .build()
+    }
+
+    fn session() -> Session {
+        let history = vec![message(Role::User, "Hi"), message(Role::Assistant, "Hello")];
+        Session {
+            hash: "hash".to_owned(),
+            upstream: history.clone(),
+            history,
+        }
+    }
+
+    #[test]
+    fn new_turns_strips_resent_history() {
+        let messages = vec![
+            message(Role::User, "Hi"),
+            message(Role::Assistant, "Hello"),
+            message(Role::User, "Bye"),
+        ];
+        assert_eq!(
+            session().new_turns(&messages, false),
+            Some(vec![message(Role::User, "Bye")])
+        );
+    }
+
+    #[test]
+    fn new_turns_accepts_only_new_messages() {
+        let messages = vec![message(Role::User, "Bye")];
+        assert_eq!(session().new_turns(&messages, true), Some(messages));
+    }
+
+    #[test]
+    fn new_turns_needs_history_without_explicit_conversation() {
+        let messages = vec![message(Role::User, "Bye")];
+        assert_eq!(session().new_turns(&messages, false), None);
+    }
+
+    #[test]
+    fn new_turns_rejects_diverging_history() {
+        let messages = vec![
+            message(Role::User, "Hi"),
+            message(Role::Assistant, "Something else"),
+            message(Role::User, "Bye"),
+        ];
+        assert_eq!(session().new_turns(&messages, true), None);
+        assert_eq!(session().new_turns(&session().history, true), None);
+    }
+
+    #[test]
+    fn conversation_id_prefers_header() {
+        let conversation = |id: &str, explicit| {
+            Some(Conversation {
+                id: id.to_owned(),
+                explicit,
+            })
+        };
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            conversation_id(&headers, Some("user"), None),
+            conversation("user", false)
+        );
+        headers.insert(CONVERSATION_HEADER, "conv".parse().unwrap());
+        assert_eq!(
+            conversation_id(&headers, Some("user"), None),
+            conversation("conv", true)
+        );
+        assert_eq!(conversation_id(&HeaderMap::new(), Some(""), None), None);
+    }
+
+    #[test]
+    fn conversation_id_is_scoped_to_the_key() {
+        let key = |name: &str| ApiKey {
+            name: name.to_owned(),
+            key: None,
+            key_hash: None,
+            enabled: true,
+            models: Vec::new(),
+            expires_at: None,
+        };
+        let id = |key: &ApiKey| conversation_id(&HeaderMap::new(), Some("user"), Some(key));
+        let (alice, bob) = (key("alice"), key("bob"));
+        assert_eq!(id(&alice), id(&alice));
+        assert_ne!(id(&alice), id(&bob));
+        assert_ne!(
+            auth::scoped_id(Some(&key("a:b")), "c"),
+            auth::scoped_id(Some(&key("a")), "b:c")
+        );
+    }
+}
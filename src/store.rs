@@ -0,0 +1,133 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// In-memory key/value store with idle expiry and a bounded size.
+///
+/// When full, inserting evicts the least recently used entry.
+pub struct TtlStore<V> {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<String, Entry<V>>>,
+}
+
+struct Entry<V> {
+    value: V,
+    touched: Instant,
+}
+
+impl<V: Clone> TtlStore<V> {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get a live entry, refreshing its expiry
+    pub fn get(&self, key: &str) -> Option<V> {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        match entries.get_mut(key) {
+            Some(entry) if now.duration_since(entry.touched) < self.ttl => {
+                entry.touched = now;
+                Some(entry.value.clone())
+            }
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Insert or replace an entry. A zero capacity store keeps nothing.
+    pub fn insert(&self, key: String, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        entries.retain(|_, entry| now.duration_since(entry.touched) < self.ttl);
+        if !entries.contains_key(&key) && entries.len() >= self.capacity {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.touched)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            key,
+            Entry {
+                value,
+                touched: now,
+            },
+        );
+    }
+
+    pub fn remove(&self, key: &str) -> Option<V> {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(key)
+            .map(|entry| entry.value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_returns_inserted_value() {
+        let store = TtlStore::new(Duration::from_secs(60), 4);
+        store.insert("a".to_owned(), 1);
+        assert_eq!(store.get("a"), Some(1));
+        assert_eq!(store.get("b"), None);
+        assert_eq!(store.remove("a"), Some(1));
+        assert_eq!(store.get("a"), None);
+    }
+
+    #[test]
+    fn expired_entries_are_evicted() {
+        let store = TtlStore::new(Duration::from_millis(10), 4);
+        store.insert("a".to_owned(), 1);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(store.get("a"), None);
+        assert_eq!(store.len(), 0);
+    }
+
+    #[test]
+    fn capacity_evicts_least_recently_used() {
+        let store = TtlStore::new(Duration::from_secs(60), 2);
+        store.insert("a".to_owned(), 1);
+        std::thread::sleep(Duration::from_millis(2));
+        store.insert("b".to_owned(), 2);
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(store.get("a"), Some(1));
+        store.insert("c".to_owned(), 3);
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get("b"), None);
+        assert_eq!(store.get("a"), Some(1));
+        assert_eq!(store.get("c"), Some(3));
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let store = TtlStore::new(Duration::from_secs(60), 0);
+        store.insert("a".to_owned(), 1);
+        assert_eq!(store.get("a"), None);
+    }
+}
@@ -0,0 +1,264 @@
+//! Token count estimation.
+//!
+//! The upstream reports no usage and its tokenizers aren't available
+//! locally, so counts are estimated: text is split with a BPE-style
+//! pre-tokenizer, and each piece is costed by the average characters per
+//! token of the model family's vocabulary.
+
+use crate::model::Message;
+use regex::Regex;
+use std::sync::LazyLock;
+
+/// GPT-style pre-tokenization: contractions, words, 1-3 digit groups,
+/// punctuation runs and whitespace runs
+static PRE_TOKENIZER: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i:'s|'t|'re|'ve|'m|'ll|'d)| ?\p{L}+| ?\p{N}{1,3}| ?[^\s\p{L}\p{N}]+|\s+")
+        .expect("invalid pre-tokenizer pattern")
+});
+
+/// Tokenizer family of a model
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Family {
+    /// o200k vocabulary (gpt-4o, o-series)
+    OpenAi,
+    Claude,
+    /// Llama 3 tiktoken-based vocabulary
+    Llama,
+    Mistral,
+    /// Unknown models, costed like the cl100k vocabulary
+    Other,
+}
+
+impl Family {
+    fn of(model: &str) -> Self {
+        let model = model.to_ascii_lowercase();
+        let o_series = model.starts_with('o') && model[1..].starts_with(char::is_numeric);
+        if model.starts_with("gpt-") || o_series {
+            Family::OpenAi
+        } else if model.contains("claude") {
+            Family::Claude
+        } else if model.contains("llama") {
+            Family::Llama
+        } else if model.contains("mistral") || model.contains("mixtral") {
+            Family::Mistral
+        } else {
+            Family::Other
+        }
+    }
+
+    /// Average characters per token for latin words
+    fn chars_per_token(self) -> f32 {
+        match self {
+            Family::OpenAi => 4.4,
+            Family::Llama => 4.2,
+            Family::Other => 4.0,
+            Family::Claude => 3.6,
+            Family::Mistral => 3.4,
+        }
+    }
+
+    /// Tokens added per message by the chat template
+    fn message_overhead(self) -> u32 {
+        match self {
+            Family::OpenAi | Family::Other => 3,
+            Family::Claude => 2,
+            Family::Llama => 4,
+            Family::Mistral => 2,
+        }
+    }
+}
+
+/// Estimated token count of `text` for `model`
+pub fn count_text(model: &str, text: &str) -> u32 {
+    let cpt = Family::of(model).chars_per_token();
+    PRE_TOKENIZER
+        .find_iter(text)
+        .map(|piece| count_piece(piece.as_str(), cpt))
+        .sum()
+}
+
+/// Estimated prompt token count of `messages` for `model`, including the
+/// chat template overhead
+pub fn count_messages(model: &str, messages: &[Message]) -> u32 {
+    let family = Family::of(model);
+    let content: u32 = messages
+        .iter()
+        .filter_map(|message| message.content.as_ref())
+        .map(|content| count_text(model, &content.to_text()))
+        .sum();
+    // every reply is primed with the assistant header
+    content + family.message_overhead() * (messages.len() as u32 + 1)
+}
+
+/// Estimated token count of a text that grows as a reply streams in
+pub struct TokenCounter {
+    chars_per_token: f32,
+    text: String,
+    /// Length of the prefix whose pieces can no longer change
+    stable_len: usize,
+    stable_tokens: u32,
+}
+
+impl TokenCounter {
+    pub fn new(model: &str) -> Self {
+        Self {
+            chars_per_token: Family::of(model).chars_per_token(),
+            text: String::new(),
+            stable_len: 0,
+            stable_tokens: 0,
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Append `delta`, returning the estimated token count of the whole text
+    pub fn push(&mut self, delta: &str) -> u32 {
+        self.text.push_str(delta);
+        // every piece but the last is final, the last may still grow
+        let tail = &self.text[self.stable_len..];
+        let mut tokens = self.stable_tokens;
+        let mut pieces = PRE_TOKENIZER.find_iter(tail).peekable();
+        while let Some(piece) = pieces.next() {
+            let count = count_piece(piece.as_str(), self.chars_per_token);
+            if pieces.peek().is_some() {
+                self.stable_len += piece.len();
+                self.stable_tokens += count;
+            }
+            tokens += count;
+        }
+        tokens
+    }
+
+    /// Cut the text after the last piece that fits in `max` tokens
+    pub fn truncate(&mut self, max: u32) {
+        let mut tokens = 0;
+        let mut len = 0;
+        for piece in PRE_TOKENIZER.find_iter(&self.text) {
+            tokens += count_piece(piece.as_str(), self.chars_per_token);
+            if tokens > max {
+                break;
+            }
+            len += piece.len();
+        }
+        self.text.truncate(len);
+        self.stable_len = 0;
+        self.stable_tokens = 0;
+    }
+}
+
+fn count_piece(piece: &str, chars_per_token: f32) -> u32 {
+    let word = piece.trim_start_matches(' ');
+    if word.is_empty() || word.chars().all(char::is_whitespace) {
+        return 1;
+    }
+
+    let mut latin = 0usize;
+    let mut tokens = 0u32;
+    for c in word.chars() {
+        if c.is_ascii() {
+            latin += 1;
+        } else if c.is_alphabetic() && c.len_utf8() == 3 {
+            // CJK and other wide scripts take about a token per character
+            tokens += 1;
+        } else {
+            // accented letters and symbols are split into byte tokens more often
+            latin += c.len_utf8();
+        }
+    }
+
+    if word
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_punctuation())
+    {
+        // punctuation merges poorly, about two characters per token
+        return tokens + latin.div_ceil(2) as u32;
+    }
+    if latin == 0 {
+        return tokens;
+    }
+    // common words up to about two tokens' worth of characters are whole
+    // vocabulary entries, longer ones split into chunks
+    let whole = 2.0 * chars_per_token;
+    let extra = ((latin as f32 - whole) / chars_per_token).ceil().max(0.0);
+    tokens + 1 + extra as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Content, Role};
+
+    #[test]
+    fn family_of_model() {
+        assert_eq!(Family::of("gpt-4o-mini"), Family::OpenAi);
+        assert_eq!(Family::of("o4-mini"), Family::OpenAi);
+        assert_eq!(Family::of("claude-3-haiku-20240307"), Family::Claude);
+        assert_eq!(
+            Family::of("meta-llama/Llama-3.3-70B-Instruct-Turbo"),
+            Family::Llama
+        );
+        assert_eq!(
+            Family::of("mistralai/Mistral-Small-24B-Instruct-2501"),
+            Family::Mistral
+        );
+        assert_eq!(Family::of("openchat"), Family::Other);
+    }
+
+    #[test]
+    fn count_text_short_words() {
+        assert_eq!(count_text("gpt-4o-mini", ""), 0);
+        assert_eq!(count_text("gpt-4o-mini", "Hello, world!"), 4);
+        assert_eq!(count_text("gpt-4o-mini", "The quick brown fox"), 4);
+        assert_eq!(count_text("gpt-4o-mini", "12345"), 2);
+    }
+
+    #[test]
+    fn count_text_is_within_range_of_real_tokenizers() {
+        // about 35 tokens with the o200k and cl100k vocabularies
+        let text = "Rust is a multi-paradigm, general-purpose programming language \
+            that emphasizes performance, type safety, and concurrency. It enforces \
+            memory safety without a garbage collector.";
+        let count = count_text("gpt-4o-mini", text);
+        assert!((30..=42).contains(&count), "{count}");
+    }
+
+    #[test]
+    fn count_text_wide_scripts() {
+        assert_eq!(count_text("gpt-4o-mini", "你好世界"), 4);
+    }
+
+    #[test]
+    fn token_counter_matches_count_text() {
+        let text = "Streaming replies arrive in fragments, splitting words like extraordinary.";
+        let mut counter = TokenCounter::new("gpt-4o-mini");
+        let mut tokens = 0;
+        for chunk in text.as_bytes().chunks(5) {
+            tokens = counter.push(std::str::from_utf8(chunk).unwrap());
+        }
+        assert_eq!(tokens, count_text("gpt-4o-mini", text));
+        assert_eq!(counter.text(), text);
+    }
+
+    #[test]
+    fn token_counter_truncates_at_piece_boundary() {
+        let mut counter = TokenCounter::new("gpt-4o-mini");
+        counter.push("Hello, world! How are you?");
+        counter.truncate(4);
+        assert_eq!(counter.text(), "Hello, world!");
+        assert_eq!(count_text("gpt-4o-mini", counter.text()), 4);
+    }
+
+    #[test]
+    fn count_messages_adds_template_overhead() {
+        let messages = vec![
+            Message::builder()
+                .role(Role::User)
+                .content(Content::Text("Hello, world!".to_owned()))
+                .build(),
+        ];
+        assert_eq!(count_messages("gpt-4o-mini", &messages), 4 + 3 * 2);
+    }
+}
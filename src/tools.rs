@@ -0,0 +1,411 @@
+//! Tool calling emulated on top of the text-only upstream.
+//!
+//! The tool schemas are described to the model in a prompt asking it to
+//! answer with a JSON object when it wants to call tools. Replies in that
+//! shape become `tool_calls`, and tool calls and results in the history are
+//! rendered back into text for the upstream.
+
+use crate::model::{ChatRequest, Content, Message, Role, gen_id};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+// ==================== Request Body ====================
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tool {
+    #[serde(rename = "type", default = "function_type")]
+    pub r#type: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    /// `none`, `auto` or `required`
+    Mode(ToolChoiceMode),
+    /// A specific function, `{"type": "function", "function": {"name": ...}}`
+    Function { function: FunctionName },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChoiceMode {
+    None,
+    Auto,
+    Required,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct FunctionName {
+    pub name: String,
+}
+
+/// Tool call made by the assistant, in requests and replies
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    /// Position of the call, only in streamed deltas
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    pub id: String,
+    #[serde(rename = "type", default = "function_type")]
+    pub r#type: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON encoded arguments
+    pub arguments: String,
+}
+
+fn function_type() -> String {
+    "function".to_owned()
+}
+
+/// Reply shape the model is asked to use for tool calls
+#[derive(Deserialize)]
+struct EmulatedReply {
+    tool_calls: Vec<EmulatedCall>,
+}
+
+#[derive(Deserialize)]
+struct EmulatedCall {
+    name: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+impl ChatRequest {
+    /// Tools the model may call, as restricted by `tool_choice`
+    pub fn callable_tools(&self) -> Vec<Tool> {
+        match &self.tool_choice {
+            Some(ToolChoice::Mode(ToolChoiceMode::None)) => Vec::new(),
+            Some(ToolChoice::Function { function }) => self
+                .tools
+                .iter()
+                .filter(|tool| tool.function.name == function.name)
+                .cloned()
+                .collect(),
+            _ => self.tools.clone(),
+        }
+    }
+
+    /// Describe the callable tools to the model in a leading user message,
+    /// returning the tools whose calls are parsed from the reply
+    pub fn inject_tools(&mut self) -> Vec<Tool> {
+        let tools = self.callable_tools();
+        if !tools.is_empty() {
+            let required = match &self.tool_choice {
+                Some(ToolChoice::Mode(ToolChoiceMode::Required)) => Some(None),
+                Some(ToolChoice::Function { function }) => Some(Some(function.name.as_str())),
+                _ => None,
+            };
+            let prompt = Message::builder()
+                .role(Role::User)
+                .content(Content::Text(prompt(&tools, required)))
+                .build();
+            self.messages.insert(0, prompt);
+        }
+        tools
+    }
+}
+
+/// Instructions for calling `tools`; `required` is `Some` when a call is
+/// mandatory, naming the function if one is forced
+fn prompt(tools: &[Tool], required: Option<Option<&str>>) -> String {
+    let mut prompt = String::from(
+        "You have access to the following tools, given with the JSON schema of their arguments:\n",
+    );
+    for tool in tools {
+        let function = &tool.function;
+        prompt.push_str(&format!("\n- {}", function.name));
+        if let Some(description) = &function.description {
+            prompt.push_str(&format!(": {description}"));
+        }
+        let parameters = function.parameters.clone().unwrap_or_else(|| json!({}));
+        prompt.push_str(&format!("\n  arguments: {parameters}"));
+    }
+    prompt.push_str(
+        "\n\nTo call tools, reply with only a JSON object of this form and no other text:\n\
+         {\"tool_calls\": [{\"name\": \"<tool name>\", \"arguments\": {<arguments>}}]}\n\
+         Tool results are sent back in messages starting with \"Tool result\".\n",
+    );
+    prompt.push_str(&match required {
+        None => "When no tool is needed, answer normally.".to_owned(),
+        Some(None) => "You must call at least one tool.".to_owned(),
+        Some(Some(name)) => format!("You must call the tool `{name}`."),
+    });
+    prompt
+}
+
+/// Text of the assistant's `tool_calls`, as the model is asked to write them
+pub fn render_calls(calls: &[ToolCall]) -> String {
+    let calls: Vec<Value> = calls
+        .iter()
+        .map(|call| {
+            let arguments = serde_json::from_str(&call.function.arguments)
+                .unwrap_or_else(|_| Value::String(call.function.arguments.clone()));
+            let mut call_json = json!({"name": call.function.name, "arguments": arguments});
+            if !call.id.is_empty() {
+                call_json["id"] = Value::String(call.id.clone());
+            }
+            call_json
+        })
+        .collect();
+    json!({ "tool_calls": calls }).to_string()
+}
+
+/// Render tool calls and tool results of a client message into text
+pub fn render_message(message: &mut Message) {
+    if let Some(function) = message.function_call.take() {
+        message.tool_calls.get_or_insert_default().push(ToolCall {
+            index: None,
+            id: String::new(),
+            r#type: function_type(),
+            function,
+        });
+    }
+    if let Some(calls) = message.tool_calls.take() {
+        let calls = render_calls(&calls);
+        let text = match message.content.take().map(|content| content.to_text()) {
+            Some(text) if !text.is_empty() => format!("{text}\n{calls}"),
+            _ => calls,
+        };
+        message.content = Some(Content::Text(text));
+    }
+    if let Some(Role::Tool | Role::Function) = message.role {
+        // legacy function results name the function instead of a call
+        let id = message
+            .tool_call_id
+            .take()
+            .or_else(|| message.name.take())
+            .unwrap_or_default();
+        let result = message
+            .content
+            .take()
+            .map(|content| content.to_text())
+            .unwrap_or_default();
+        message.content = Some(Content::Text(format!(
+            "Tool result for call {id}:\n{result}"
+        )));
+    }
+}
+
+/// Tool calls in `reply`, if it is a call of the given `tools`
+pub fn parse_calls(reply: &str, tools: &[Tool]) -> Option<Vec<ToolCall>> {
+    let reply = reply.trim();
+    let json = reply
+        .strip_prefix("```json")
+        .or_else(|| reply.strip_prefix("```"))
+        .and_then(|fenced| fenced.strip_suffix("```"))
+        .unwrap_or(reply);
+    let reply: EmulatedReply = serde_json::from_str(json.trim()).ok()?;
+    if reply.tool_calls.is_empty() {
+        return None;
+    }
+
+    reply
+        .tool_calls
+        .into_iter()
+        .map(|call| {
+            tools
+                .iter()
+                .any(|tool| tool.function.name == call.name)
+                .then(|| ToolCall {
+                    index: None,
+                    id: gen_id("call"),
+                    r#type: function_type(),
+                    function: FunctionCall {
+                        name: call.name,
+                        arguments: match call.arguments {
+                            Value::String(arguments) => arguments,
+                            Value::Null => "{}".to_owned(),
+                            arguments => arguments.to_string(),
+                        },
+                    },
+                })
+        })
+        .collect()
+}
+
+/// What a reply turned out to be, decided from its first characters
+enum Detection {
+    Undecided,
+    /// Held back until complete, it may be a tool call
+    Calls,
+    Text,
+}
+
+/// Splits a streamed reply into plain text and emulated tool calls
+pub struct ToolCallDetector {
+    tools: Vec<Tool>,
+    detection: Detection,
+    held: String,
+}
+
+/// Complete reply once tool calls are parsed
+pub enum Reply {
+    ToolCalls(Vec<ToolCall>),
+    /// Text still held back
+    Text(String),
+}
+
+impl ToolCallDetector {
+    pub fn new(tools: Vec<Tool>) -> Self {
+        let detection = if tools.is_empty() {
+            Detection::Text
+        } else {
+            Detection::Undecided
+        };
+        Self {
+            tools,
+            detection,
+            held: String::new(),
+        }
+    }
+
+    /// Accept `delta`, returning the text that is known not to be a tool call
+    pub fn push(&mut self, delta: &str) -> String {
+        match self.detection {
+            Detection::Text => return delta.to_owned(),
+            Detection::Calls => {
+                self.held.push_str(delta);
+                return String::new();
+            }
+            Detection::Undecided => self.held.push_str(delta),
+        }
+        match self.held.trim_start().chars().next() {
+            None => String::new(),
+            Some('{' | '`') => {
+                self.detection = Detection::Calls;
+                String::new()
+            }
+            Some(_) => {
+                self.detection = Detection::Text;
+                std::mem::take(&mut self.held)
+            }
+        }
+    }
+
+    /// End of the reply: the tool calls, or the text held back
+    pub fn finish(&mut self) -> Reply {
+        let held = std::mem::take(&mut self.held);
+        if let Detection::Calls = self.detection
+            && let Some(calls) = parse_calls(&held, &self.tools)
+        {
+            return Reply::ToolCalls(calls);
+        }
+        Reply::Text(held)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weather() -> Tool {
+        serde_json::from_value(json!({
+            "type": "function",
+            "function": {
+                "name": "get_weather",
+                "description": "Current weather of a city",
+                "parameters": {"type": "object", "properties": {"city": {"type": "string"}}},
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parse_calls_of_known_tools() {
+        let reply = r#"```json
+{"tool_calls": [{"name": "get_weather", "arguments": {"city": "Paris"}}]}
+```"#;
+        let calls = parse_calls(reply, &[weather()]).unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function.name, "get_weather");
+        assert_eq!(calls[0].function.arguments, r#"{"city":"Paris"}"#);
+        assert!(calls[0].id.starts_with("call_"));
+
+        let unknown = r#"{"tool_calls": [{"name": "rm_rf", "arguments": {}}]}"#;
+        assert_eq!(parse_calls(unknown, &[weather()]), None);
+        assert_eq!(parse_calls("It is sunny.", &[weather()]), None);
+        assert_eq!(parse_calls(r#"{"tool_calls": []}"#, &[weather()]), None);
+    }
+
+    #[test]
+    fn tool_choice_restricts_callable_tools() {
+        let mut other = weather();
+        other.function.name = "get_time".to_owned();
+        let request = |tool_choice: Value| -> ChatRequest {
+            serde_json::from_value(json!({
+                "model": "gpt-4o-mini",
+                "messages": [{"role": "user", "content": "Hi"}],
+                "tools": [weather(), other],
+                "tool_choice": tool_choice,
+            }))
+            .unwrap()
+        };
+
+        assert_eq!(request(json!("auto")).callable_tools().len(), 2);
+        assert!(request(json!("none")).callable_tools().is_empty());
+        let forced = request(json!({"type": "function", "function": {"name": "get_time"}}));
+        assert_eq!(forced.callable_tools()[0].function.name, "get_time");
+
+        let mut required = request(json!("required"));
+        required.inject_tools();
+        let prompt = required.messages[0].content.as_ref().unwrap().to_text();
+        assert!(prompt.contains("- get_weather: Current weather of a city"));
+        assert!(prompt.contains("You must call at least one tool."));
+    }
+
+    #[test]
+    fn tool_messages_are_rendered_as_text() {
+        let request: ChatRequest = serde_json::from_value(json!({
+            "model": "gpt-4o-mini",
+            "messages": [
+                {"role": "user", "content": "Weather in Paris?"},
+                {"role": "assistant", "content": null, "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"},
+                }]},
+                {"role": "tool", "tool_call_id": "call_1", "content": "Sunny"},
+            ],
+        }))
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(&request.messages).unwrap(),
+            json!([
+                {"role": "user", "content": "Weather in Paris?"},
+                {"role": "assistant", "content": r#"{"tool_calls":[{"arguments":{"city":"Paris"},"id":"call_1","name":"get_weather"}]}"#},
+                {"role": "user", "content": "Tool result for call call_1:\nSunny"},
+            ])
+        );
+    }
+
+    #[test]
+    fn detector_streams_text_and_holds_calls() {
+        let mut detector = ToolCallDetector::new(vec![weather()]);
+        assert_eq!(detector.push(" "), "");
+        assert_eq!(detector.push("It is"), " It is");
+        assert_eq!(detector.push(" sunny"), " sunny");
+
+        let mut detector = ToolCallDetector::new(vec![weather()]);
+        assert_eq!(detector.push("{\"tool_calls\": [{\"name\": "), "");
+        assert_eq!(detector.push("\"get_weather\"}]}"), "");
+        assert!(matches!(detector.finish(), Reply::ToolCalls(calls) if calls.len() == 1));
+
+        let mut detector = ToolCallDetector::new(vec![weather()]);
+        detector.push("{not json");
+        assert!(matches!(detector.finish(), Reply::Text(text) if text == "{not json"));
+    }
+}